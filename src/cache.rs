@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use crate::error::{Error, Operation};
+use crate::fs::Metadata;
+
+
+
+/// persistent cache of content hashes keyed by device and inode
+/// an entry is only used while the size, mtime and ctime of its inode are unchanged
+/// entries not used for MAX_AGE are dropped when saving
+pub struct HashCache {
+    path: PathBuf,
+    entries: std::sync::Mutex<HashMap<(u64, u64), HashCacheEntry>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct HashCacheStamp {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    ctime: i64,
+    ctime_nsec: i64,
+}
+
+struct HashCacheEntry {
+    stamp: HashCacheStamp,
+    partial: Option<u64>,
+    full: Option<u128>,
+    /// when last looked up or stored, in seconds since the epoch
+    last_used: i64,
+}
+
+impl HashCacheStamp {
+    fn new(md: &Metadata) -> Self {
+        HashCacheStamp {
+            size: md.st_size(),
+            mtime: md.st_mtime(),
+            mtime_nsec: md.st_mtime_nsec(),
+            ctime: md.st_ctime(),
+            ctime_nsec: md.st_ctime_nsec(),
+        }
+    }
+}
+
+impl HashCache {
+    const HEADER: &str = "lndups-hash-cache 1";
+    /// how long an entry is kept without being used
+    /// the cache is shared by runs over different trees, so an unused entry may still be of use later
+    pub const MAX_AGE: std::time::Duration = std::time::Duration::from_secs(30 * 24 * 60 * 60);
+
+    /// $XDG_CACHE_HOME/lndups/hashes, falling back to ~/.cache/lndups/hashes
+    pub fn default_path() -> Option<PathBuf> {
+        let base = match std::env::var_os("XDG_CACHE_HOME").filter(|s| !s.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME").filter(|s| !s.is_empty())?).join(".cache"),
+        };
+        Some(base.join(env!("CARGO_PKG_NAME")).join("hashes"))
+    }
+
+    /// a nonexistent file results in an empty cache
+    /// malformed entries are ignored
+    pub fn load(path: PathBuf) -> Result<Self, Error> {
+        let mut entries = HashMap::new();
+        match std::fs::File::open(&path) {
+            Ok(f) => {
+                let mut lines = std::io::BufReader::new(f).lines();
+                let header = lines.next().transpose().map_err(|e| Error::io(Operation::Read, &path, e))?;
+                if header.as_deref() == Some(Self::HEADER) {
+                    for line in lines {
+                        let line = line.map_err(|e| Error::io(Operation::Read, &path, e))?;
+                        if let Some((key, entry)) = Self::parse_line(&line) {
+                            entries.insert(key, entry);
+                        }
+                    }
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(Error::io(Operation::Open, path, e)),
+        }
+        Ok(HashCache { path, entries: std::sync::Mutex::new(entries) })
+    }
+
+    /// atomically replace the cache file
+    /// drops entries not used for MAX_AGE
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| Error::io(Operation::Create, dir, e))?;
+        }
+        let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(format!(".tmp-{}", std::process::id()));
+        let tmp = self.path.with_file_name(tmp_name);
+        let result = (|| {
+            let mut buf = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+            writeln!(buf, "{}", Self::HEADER)?;
+            let oldest = now() - Self::MAX_AGE.as_secs() as i64;
+            for ((dev, ino), entry) in self.entries.lock().unwrap().iter() {
+                if entry.last_used < oldest {
+                    continue;
+                }
+                let s = &entry.stamp;
+                writeln!(buf, "{} {} {} {} {} {} {} {} {} {}",
+                    dev, ino, s.size, s.mtime, s.mtime_nsec, s.ctime, s.ctime_nsec,
+                    entry.partial.map_or("-".to_string(), |h| format!("{:x}", h)),
+                    entry.full.map_or("-".to_string(), |h| format!("{:x}", h)),
+                    entry.last_used,
+                )?;
+            }
+            buf.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            std::fs::rename(&tmp, &self.path)
+        })();
+        result.map_err(|e| {
+            let _ = std::fs::remove_file(&tmp);
+            Error::io(Operation::Write, &self.path, e)
+        })
+    }
+
+    /// cached partial hash of the inode described by md, computing and storing it if absent
+    pub fn partial(
+        &self,
+        md: &Metadata,
+        compute: impl FnOnce() -> std::io::Result<u64>
+    ) -> std::io::Result<u64> {
+        if let Some(h) = self.lookup(md, |entry| entry.partial) {
+            return Ok(h);
+        }
+        let h = compute()?;
+        self.store(md, |entry| entry.partial = Some(h));
+        Ok(h)
+    }
+
+    /// cached full hash of the inode described by md, computing and storing it if absent
+    pub fn full(
+        &self,
+        md: &Metadata,
+        compute: impl FnOnce() -> std::io::Result<u128>
+    ) -> std::io::Result<u128> {
+        if let Some(h) = self.lookup(md, |entry| entry.full) {
+            return Ok(h);
+        }
+        let h = compute()?;
+        self.store(md, |entry| entry.full = Some(h));
+        Ok(h)
+    }
+
+    /// update the stamp of an existing entry after a metadata change known not to affect contents
+    pub fn restamp(&self, md: &Metadata) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&(md.st_dev(), md.st_ino())) {
+            entry.stamp = HashCacheStamp::new(md);
+            entry.last_used = now();
+        }
+    }
+
+    /// drop the entry of an inode that no longer exists
+    pub fn forget(&self, md: &Metadata) {
+        self.entries.lock().unwrap().remove(&(md.st_dev(), md.st_ino()));
+    }
+
+    fn lookup<T>(&self, md: &Metadata, field: impl FnOnce(&HashCacheEntry) -> Option<T>) -> Option<T> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(&(md.st_dev(), md.st_ino()))?;
+        entry.last_used = now();
+        if entry.stamp != HashCacheStamp::new(md) {
+            return None;
+        }
+        field(entry)
+    }
+
+    fn store(&self, md: &Metadata, update: impl FnOnce(&mut HashCacheEntry)) {
+        let stamp = HashCacheStamp::new(md);
+        let last_used = now();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry((md.st_dev(), md.st_ino()))
+            .or_insert(HashCacheEntry { stamp, partial: None, full: None, last_used });
+        if entry.stamp != stamp {
+            *entry = HashCacheEntry { stamp, partial: None, full: None, last_used };
+        }
+        entry.last_used = last_used;
+        update(entry);
+    }
+
+    fn parse_line(line: &str) -> Option<((u64, u64), HashCacheEntry)> {
+        let mut fields = line.split(' ');
+        let mut next = || fields.next();
+        let dev = next()?.parse().ok()?;
+        let ino = next()?.parse().ok()?;
+        let stamp = HashCacheStamp {
+            size: next()?.parse().ok()?,
+            mtime: next()?.parse().ok()?,
+            mtime_nsec: next()?.parse().ok()?,
+            ctime: next()?.parse().ok()?,
+            ctime_nsec: next()?.parse().ok()?,
+        };
+        let partial = match next()? {
+            "-" => None,
+            h => Some(u64::from_str_radix(h, 16).ok()?),
+        };
+        let full = match next()? {
+            "-" => None,
+            h => Some(u128::from_str_radix(h, 16).ok()?),
+        };
+        let last_used = next()?.parse().ok()?;
+        if next().is_some() {
+            return None;
+        }
+        Some(((dev, ino), HashCacheEntry { stamp, partial, full, last_used }))
+    }
+}
+
+/// seconds since the epoch
+fn now() -> i64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::fs::metadata_nofollow;
+    use crate::testing::TempDir;
+    #[test]
+    pub fn test_hash_cache_roundtrip() {
+        let dir = TempDir::new("cache");
+        std::fs::write(dir.join("a"), b"contents").unwrap();
+        let md = metadata_nofollow(&dir.join("a")).unwrap();
+        let cache = HashCache::load(dir.join("cache")).unwrap();
+        assert_eq!(cache.full(&md, || Ok(42)).unwrap(), 42);
+        cache.save().unwrap();
+        let cache = HashCache::load(dir.join("cache")).unwrap();
+        assert_eq!(cache.full(&md, || panic!("not cached")).unwrap(), 42);
+        assert_eq!(cache.partial(&md, || Ok(7)).unwrap(), 7);
+        std::fs::write(dir.join("a"), b"changed!").unwrap();
+        let md = metadata_nofollow(&dir.join("a")).unwrap();
+        assert_eq!(cache.full(&md, || Ok(43)).unwrap(), 43);
+    }
+    #[test]
+    pub fn test_hash_cache_drops_only_old_entries() {
+        let dir = TempDir::new("cache-prune");
+        std::fs::write(dir.join("a"), b"a").unwrap();
+        std::fs::write(dir.join("b"), b"b").unwrap();
+        let md_a = metadata_nofollow(&dir.join("a")).unwrap();
+        let md_b = metadata_nofollow(&dir.join("b")).unwrap();
+        let cache = HashCache::load(dir.join("cache")).unwrap();
+        cache.full(&md_a, || Ok(1)).unwrap();
+        cache.full(&md_b, || Ok(2)).unwrap();
+        cache.save().unwrap();
+        let old = format!("{} 1 0 0 0 0 0 - 3 {}", md_a.st_dev(), now() - HashCache::MAX_AGE.as_secs() as i64 - 1);
+        let mut file = std::fs::OpenOptions::new().append(true).open(dir.join("cache")).unwrap();
+        writeln!(file, "{}", old).unwrap();
+
+        // a run over other files on the same device keeps the entries of b
+        let cache = HashCache::load(dir.join("cache")).unwrap();
+        cache.full(&md_a, || panic!("not cached")).unwrap();
+        cache.save().unwrap();
+        let contents = std::fs::read_to_string(dir.join("cache")).unwrap();
+        assert!(!contents.lines().any(|line| line == old));
+        let cache = HashCache::load(dir.join("cache")).unwrap();
+        assert_eq!(cache.full(&md_a, || panic!("not cached")).unwrap(), 1);
+        assert_eq!(cache.full(&md_b, || panic!("not cached")).unwrap(), 2);
+    }
+}
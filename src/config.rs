@@ -0,0 +1,222 @@
+use crate::cache::HashCache;
+use crate::journal::Journal;
+use crate::progress::Progress;
+use crate::signal::interrupted;
+
+
+
+/// smallest file size ever considered; empty files are never linked
+pub const ABSOLUTE_MIN_SIZE: u64 = 1;
+
+
+/// how a run is performed; built with [ConfigBuilder]
+pub struct Config {
+    pub(crate) dry_run: bool,
+    pub(crate) report: bool,
+    pub(crate) verify: bool,
+    pub(crate) require_same_owner: bool,
+    pub(crate) require_same_mode: bool,
+    pub(crate) require_same_xattrs: bool,
+    pub(crate) auto_split_devices: bool,
+    pub(crate) one_file_system: bool,
+    pub(crate) max_depth: Option<usize>,
+    pub(crate) link_mode: LinkMode,
+    pub(crate) keep: KeepPolicy,
+    pub(crate) min_size: u64,
+    pub(crate) exclude: Vec<glob::Pattern>,
+    pub(crate) include: Vec<glob::Pattern>,
+    pub(crate) hash_cache: Option<HashCache>,
+    pub(crate) journal: Option<Journal>,
+    pub(crate) progress: Option<std::sync::Arc<Progress>>,
+    pub(crate) cancel: Option<std::sync::Arc<std::sync::atomic::AtomicBool>>,
+}
+
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+    pub fn report(&self) -> bool {
+        self.report
+    }
+    pub fn auto_split_devices(&self) -> bool {
+        self.auto_split_devices
+    }
+    pub fn link_mode(&self) -> LinkMode {
+        self.link_mode
+    }
+    pub fn hash_cache(&self) -> Option<&HashCache> {
+        self.hash_cache.as_ref()
+    }
+    pub fn progress(&self) -> Option<&Progress> {
+        self.progress.as_deref()
+    }
+    /// whether the run should wind down, because of a signal or the cancel flag
+    pub fn cancelled(&self) -> bool {
+        interrupted().is_some()
+            || self.cancel.as_ref().is_some_and(|cancel| cancel.load(std::sync::atomic::Ordering::Relaxed))
+    }
+}
+
+
+/// builds a Config; defaults match those of the command line
+pub struct ConfigBuilder {
+    config: Config,
+}
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        ConfigBuilder { config: Config {
+            dry_run: false,
+            report: false,
+            verify: false,
+            require_same_owner: false,
+            require_same_mode: false,
+            require_same_xattrs: false,
+            auto_split_devices: false,
+            one_file_system: false,
+            max_depth: None,
+            link_mode: LinkMode::Hardlink,
+            keep: KeepPolicy::MostLinks,
+            min_size: ABSOLUTE_MIN_SIZE,
+            exclude: Vec::new(),
+            include: Vec::new(),
+            hash_cache: None,
+            journal: None,
+            progress: None,
+            cancel: None,
+        } }
+    }
+}
+impl ConfigBuilder {
+    /// perform no operations on the filesystem
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.config.dry_run = dry_run;
+        self
+    }
+    /// list each set of identical files instead of linking
+    pub fn report(mut self, report: bool) -> Self {
+        self.config.report = report;
+        self
+    }
+    /// confirm each link after it is made
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.config.verify = verify;
+        self
+    }
+    pub fn require_same_owner(mut self, require_same_owner: bool) -> Self {
+        self.config.require_same_owner = require_same_owner;
+        self
+    }
+    pub fn require_same_mode(mut self, require_same_mode: bool) -> Self {
+        self.config.require_same_mode = require_same_mode;
+        self
+    }
+    pub fn require_same_xattrs(mut self, require_same_xattrs: bool) -> Self {
+        self.config.require_same_xattrs = require_same_xattrs;
+        self
+    }
+    /// only compare files on the same device instead of requiring a single device
+    pub fn auto_split_devices(mut self, auto_split_devices: bool) -> Self {
+        self.config.auto_split_devices = auto_split_devices;
+        self
+    }
+    /// don't descend into directories on other devices than their target
+    pub fn one_file_system(mut self, one_file_system: bool) -> Self {
+        self.config.one_file_system = one_file_system;
+        self
+    }
+    /// directory levels to descend at most below each target
+    pub fn max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.config.max_depth = max_depth;
+        self
+    }
+    pub fn link_mode(mut self, link_mode: LinkMode) -> Self {
+        self.config.link_mode = link_mode;
+        self
+    }
+    pub fn keep(mut self, keep: KeepPolicy) -> Self {
+        self.config.keep = keep;
+        self
+    }
+    /// never goes below the absolute minimum size
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.config.min_size = std::cmp::max(min_size, ABSOLUTE_MIN_SIZE);
+        self
+    }
+    pub fn exclude(mut self, exclude: Vec<glob::Pattern>) -> Self {
+        self.config.exclude = exclude;
+        self
+    }
+    pub fn include(mut self, include: Vec<glob::Pattern>) -> Self {
+        self.config.include = include;
+        self
+    }
+    pub fn hash_cache(mut self, hash_cache: Option<HashCache>) -> Self {
+        self.config.hash_cache = hash_cache;
+        self
+    }
+    /// only used for hardlinks, and not in a dry run
+    pub fn journal(mut self, journal: Option<Journal>) -> Self {
+        self.config.journal = journal;
+        self
+    }
+    /// updated by scan and deduplicate
+    pub fn progress(mut self, progress: Option<std::sync::Arc<Progress>>) -> Self {
+        self.config.progress = progress;
+        self
+    }
+    /// once set, scan, deduplicate and undo stop as on a signal
+    pub fn cancel(mut self, cancel: Option<std::sync::Arc<std::sync::atomic::AtomicBool>>) -> Self {
+        self.config.cancel = cancel;
+        self
+    }
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+
+/// how duplicates are made to share storage
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LinkMode {
+    Hardlink,
+    Reflink,
+    DedupeRange,
+}
+impl LinkMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            LinkMode::Hardlink => "hardlink",
+            LinkMode::Reflink => "reflink",
+            LinkMode::DedupeRange => "dedupe_range",
+        }
+    }
+    pub fn past_tense(self) -> &'static str {
+        match self {
+            LinkMode::Hardlink => "hardlinked",
+            LinkMode::Reflink => "reflinked",
+            LinkMode::DedupeRange => "deduped",
+        }
+    }
+    pub fn past_tense_capitalized(self) -> &'static str {
+        match self {
+            LinkMode::Hardlink => "Hardlinked",
+            LinkMode::Reflink => "Reflinked",
+            LinkMode::DedupeRange => "Deduped",
+        }
+    }
+}
+
+
+/// which inode of a set of identical files survives
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum KeepPolicy {
+    MostLinks,
+    OldestMtime,
+    NewestMtime,
+    PathPriority,
+    LowestInode,
+}
@@ -0,0 +1,790 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+use smallvec::*;
+
+use crate::config::{Config, KeepPolicy, LinkMode};
+use crate::error::{Error, ErrorTally, Operation};
+use crate::event::{Event, OnEvent};
+use crate::fs::{open_regular, xattrs};
+use crate::link::{dedupe_range, hardlink, reflink, verify_link};
+use crate::scan::{PathWithMetadata, Registry};
+
+
+
+/// paths sharing one inode
+pub type InodeGroup<'a> = SmallVec<[&'a PathWithMetadata; 1]>;
+
+
+
+/// outcome of deduplicating a registry
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub files_considered: usize,
+    /// distinct inodes among the files considered
+    pub inodes_starting: usize,
+    /// inodes whose paths were linked to another inode; would have been, in a dry run or report
+    pub inodes_linked: usize,
+    /// inodes kept in addition because a kept inode reached the link limit
+    pub link_groups_started: usize,
+    pub bytes_freed: u64,
+    /// stopped early by a signal or the cancel flag of the config
+    pub interrupted: bool,
+    /// errors while hashing, comparing or linking
+    pub errors: ErrorTally,
+}
+impl std::ops::Add for Summary {
+    type Output = Summary;
+    fn add(self, other: Summary) -> Summary {
+        Summary {
+            files_considered: self.files_considered + other.files_considered,
+            inodes_starting: self.inodes_starting + other.inodes_starting,
+            inodes_linked: self.inodes_linked + other.inodes_linked,
+            link_groups_started: self.link_groups_started + other.link_groups_started,
+            bytes_freed: self.bytes_freed + other.bytes_freed,
+            interrupted: self.interrupted || other.interrupted,
+            errors: {
+                let mut errors = self.errors;
+                errors += other.errors;
+                errors
+            },
+        }
+    }
+}
+
+
+
+/// link the duplicates in registry, in parallel across sizes
+/// reports what happens to on_event
+pub fn deduplicate(
+    registry: Registry,
+    cfg: &Config,
+    on_event: OnEvent,
+) -> Summary {
+    let files_considered = registry.values().map(|files| files.len()).sum::<usize>();
+    if let Some(progress) = &cfg.progress {
+        progress.start_deduplicating(registry.len() as u64, files_considered as u64);
+    }
+
+    use rayon::prelude::*;
+    let summary = registry.into_par_iter()
+        .fold(Summary::default, |summary, (fsize, pwmds)| {
+            if cfg.cancelled() { // don't start new sizes
+                return summary;
+            }
+            let size_summary = run_one_size(fsize, &pwmds, cfg, on_event);
+            if let Some(progress) = &cfg.progress {
+                progress.finished_group(pwmds.len() as u64);
+            }
+            summary + size_summary
+        })
+        .reduce(Summary::default, |total, summary| total + summary);
+    if let Some(progress) = &cfg.progress {
+        progress.idle();
+    }
+
+    Summary {
+        files_considered, // including sizes not started before an interruption
+        interrupted: cfg.cancelled(),
+        ..summary
+    }
+}
+
+
+
+fn run_one_size(
+    fsize: u64,
+    pwmds: &[PathWithMetadata],
+    cfg: &Config,
+    on_event: OnEvent,
+) -> Summary {
+    let mut by_inode: Vec<InodeGroup>
+        = Vec::with_capacity((pwmds.len() as f64 * 0.8) as usize); // each nonempty
+    let mut inodes: Vec<(u64, u64)> = Vec::with_capacity(by_inode.capacity());
+    for pwmd in pwmds {
+        let inode: (u64, u64) = (pwmd.md().st_dev(), pwmd.md().st_ino());
+        match inodes.binary_search(&inode) {
+            Ok(i) => {
+                by_inode[i].push(pwmd);
+            },
+            Err(i) => {
+                inodes.insert(i, inode);
+                by_inode.insert(i, smallvec![pwmd]);
+            }
+        }
+    }
+    drop(inodes);
+    by_inode.sort_by_key(|b| std::cmp::Reverse(b.len())); // descending size order
+
+    let starting_inode_count = by_inode.len();
+    let mut errors = ErrorTally::default();
+
+    let by_device = match cfg.auto_split_devices {
+        true => {
+            let mut by_device: Vec<Vec<InodeGroup>> = Vec::new();
+            for pwmds in by_inode {
+                let dev = pwmds[0].md().st_dev();
+                match by_device.iter_mut().find(|group| group[0][0].md().st_dev() == dev) {
+                    Some(group) => group.push(pwmds),
+                    None => by_device.push(vec![pwmds]),
+                }
+            }
+            by_device.retain(|group| group.len() >= 2);
+            by_device
+        },
+        false => vec![by_inode],
+    };
+
+    let read = |bytes| if let Some(progress) = &cfg.progress {
+        progress.read(bytes);
+    };
+    let partial = |pwmd: &PathWithMetadata| hash_partial(&pwmd.path, fsize)
+        .inspect(|_| read(std::cmp::min(fsize, 2 * PARTIAL_HASH_BLOCK)));
+    let full = |pwmd: &PathWithMetadata| hash_full(&pwmd.path).inspect(|_| read(fsize));
+
+    // narrow down candidates before comparing any contents byte by byte
+    let mut groups = split_groups(by_device, |pwmd| match &cfg.hash_cache {
+        Some(cache) => cache.partial(&pwmd.md(), || partial(pwmd)),
+        None => partial(pwmd),
+    }, &mut errors, on_event);
+    if fsize > 2 * PARTIAL_HASH_BLOCK { // otherwise partial hash covered the entire file
+        groups = split_groups(groups, |pwmd| match &cfg.hash_cache {
+            Some(cache) => cache.full(&pwmd.md(), || full(pwmd)),
+            None => full(pwmd),
+        }, &mut errors, on_event);
+    }
+
+    let mut number_ionodes_removed = 0;
+    let mut number_link_groups_started = 0;
+    for group in groups {
+        if cfg.cancelled() {
+            break;
+        }
+        let classes: Vec<_> = partition_identical(group, cfg, &mut errors, on_event).into_iter()
+            .flat_map(|class| split_by_metadata(class, cfg, &mut errors, on_event))
+            .collect();
+        for mut class in classes {
+            let i = keeper_index(&class, cfg.keep);
+            class[..=i].rotate_right(1);
+            let paths: Vec<&Path> = class.iter().flatten().map(|pwmd| pwmd.path.as_path()).collect();
+            on_event(Event::GroupFound { size: fsize, inodes: class.len(), paths: &paths });
+            if cfg.report {
+                number_ionodes_removed += class.len() - 1;
+                continue;
+            }
+            let mut class = class.into_iter();
+            let mut keeps = class.next().unwrap();
+            for replaces in class {
+                if cfg.cancelled() {
+                    break;
+                }
+                let link = |keep: &PathWithMetadata, replace: &PathWithMetadata| share_storage(keep, replace, cfg.link_mode);
+                match hardlink_all(&mut keeps, &replaces, cfg, &mut errors, on_event, link) {
+                    Outcome::Linked => number_ionodes_removed += 1,
+                    Outcome::Failed => {},
+                    Outcome::LimitReached(rest) => {
+                        keeps = rest;
+                        number_link_groups_started += 1;
+                    },
+                }
+            }
+        }
+    }
+
+    on_event(Event::SizeDone {
+        size: fsize,
+        inodes_starting: starting_inode_count,
+        inodes_linked: number_ionodes_removed,
+    });
+    Summary {
+        files_considered: pwmds.len(),
+        inodes_starting: starting_inode_count,
+        inodes_linked: number_ionodes_removed,
+        link_groups_started: number_link_groups_started,
+        bytes_freed: fsize * number_ionodes_removed as u64,
+        interrupted: false,
+        errors,
+    }
+}
+
+
+
+/// split each group of inodes by a key of their contents, dropping resulting groups with only one member
+/// inodes for which the key can't be computed are dropped
+/// reports errors to on_event and records them in errors
+fn split_groups<'a, K>(
+    groups: Vec<Vec<InodeGroup<'a>>>,
+    key: impl Fn(&PathWithMetadata) -> std::io::Result<K>,
+    errors: &mut ErrorTally,
+    on_event: OnEvent,
+) -> Vec<Vec<InodeGroup<'a>>>
+where K: std::hash::Hash + Eq,
+{
+    let mut result = Vec::new();
+    for group in groups {
+        let mut by_key: HashMap<K, Vec<InodeGroup>> = HashMap::with_capacity(group.len());
+        for pwmds in group {
+            match key(pwmds[0]) {
+                Ok(k) => by_key.entry(k).or_default().push(pwmds),
+                Err(error) => {
+                    let error = Error::io(Operation::Read, &pwmds[0].path, error);
+                    on_event(Event::Error(&error));
+                    errors.record(&error);
+                },
+            }
+        }
+        result.extend(by_key.into_values().filter(|v| v.len() >= 2));
+    }
+    result
+}
+
+
+
+/// partition inode groups into classes with identical contents, dropping classes with only one member
+/// order is preserved within each class
+/// inodes that can't be compared are dropped
+/// reports errors to on_event and records them in errors
+fn partition_identical<'a>(
+    group: Vec<InodeGroup<'a>>,
+    cfg: &Config,
+    errors: &mut ErrorTally,
+    on_event: OnEvent,
+) -> Vec<Vec<InodeGroup<'a>>> {
+    let mut classes: Vec<Vec<InodeGroup>> = Vec::new();
+    'groups: for pwmds in group {
+        for class in classes.iter_mut() {
+            let identical = cmp(&class[0][0].path, &pwmds[0].path);
+            if let Some(progress) = &cfg.progress { // at most; comparing stops at the first difference
+                progress.read(2 * pwmds[0].md().st_size());
+            }
+            match identical {
+                Ok(true) => {
+                    class.push(pwmds);
+                    continue 'groups;
+                },
+                Ok(false) => {},
+                Err(error) => {
+                    on_event(Event::Error(&error));
+                    errors.record(&error);
+                    continue 'groups;
+                },
+            }
+        }
+        classes.push(vec![pwmds]);
+    }
+    classes.retain(|class| class.len() >= 2);
+    classes
+}
+
+
+
+/// split a class of identical inodes by the metadata required to be the same,
+/// dropping resulting classes with only one member
+/// inodes whose extended attributes can't be read are never grouped
+/// reports errors to on_event and records them in errors
+fn split_by_metadata<'a>(
+    class: Vec<InodeGroup<'a>>,
+    cfg: &Config,
+    errors: &mut ErrorTally,
+    on_event: OnEvent,
+) -> Vec<Vec<InodeGroup<'a>>> {
+    if !cfg.require_same_owner && !cfg.require_same_mode && !cfg.require_same_xattrs {
+        return vec![class];
+    }
+    type Key = (Option<(u32, u32)>, Option<u32>, Option<Vec<(Vec<u8>, Vec<u8>)>>);
+    let mut by_key: Vec<(Key, Vec<InodeGroup>)> = Vec::new(); // preserves order
+    for pwmds in class {
+        let xattrs = match cfg.require_same_xattrs {
+            true => match xattrs(&pwmds[0].path) {
+                Ok(xattrs) => Some(xattrs),
+                Err(error) => {
+                    let error = Error::io(Operation::ReadXattrs, &pwmds[0].path, error);
+                    on_event(Event::Error(&error));
+                    errors.record(&error);
+                    continue;
+                },
+            },
+            false => None,
+        };
+        let key: Key = {
+            let md = pwmds[0].md();
+            (
+                cfg.require_same_owner.then(|| (md.st_uid(), md.st_gid())),
+                cfg.require_same_mode.then(|| md.st_mode() & 0o7777),
+                xattrs,
+            )
+        };
+        match by_key.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, members)) => members.push(pwmds),
+            None => by_key.push((key, vec![pwmds])),
+        }
+    }
+    by_key.into_iter().map(|(_, members)| members).filter(|members| members.len() >= 2).collect()
+}
+
+/// index of the inode group to keep according to policy; earliest wins ties
+fn keeper_index(class: &[InodeGroup], policy: KeepPolicy) -> usize {
+    use std::cmp::Reverse;
+    let mtime = |pwmds: &InodeGroup| {
+        let md = pwmds[0].md();
+        (md.st_mtime(), md.st_mtime_nsec())
+    };
+    let best = match policy {
+        KeepPolicy::MostLinks => class.iter().enumerate()
+            .min_by_key(|(_, pwmds)| Reverse((pwmds.len(), pwmds[0].md().st_nlink()))),
+        KeepPolicy::OldestMtime => class.iter().enumerate()
+            .min_by_key(|(_, pwmds)| mtime(pwmds)),
+        KeepPolicy::NewestMtime => class.iter().enumerate()
+            .min_by_key(|(_, pwmds)| Reverse(mtime(pwmds))),
+        KeepPolicy::PathPriority => class.iter().enumerate()
+            .min_by_key(|(_, pwmds)| pwmds.iter().map(|pwmd| pwmd.target).min()),
+        KeepPolicy::LowestInode => class.iter().enumerate()
+            .min_by_key(|(_, pwmds)| pwmds[0].md().st_ino()),
+    };
+    best.map_or(0, |(i, _)| i)
+}
+
+
+
+/// what became of the inode of replaces in hardlink_all
+enum Outcome<'a> {
+    /// every path was linked, freeing the inode
+    Linked,
+    /// some path was not linked, so the inode remains
+    Failed,
+    /// the kept inode reached the link limit; the inode of these paths not yet linked
+    /// should be kept for the remaining duplicates instead
+    LimitReached(InodeGroup<'a>),
+}
+
+/// make replace share storage with keep as by mode
+/// returns the number of bytes deduped for LinkMode::DedupeRange
+fn share_storage(keep: &PathWithMetadata, replace: &PathWithMetadata, mode: LinkMode) -> Result<Option<u64>, Error> {
+    match mode {
+        LinkMode::Hardlink => hardlink(keep, replace).map(|_| None),
+        LinkMode::Reflink => reflink(keep, replace).map(|_| None),
+        LinkMode::DedupeRange => dedupe_range(keep, replace).map(Some),
+    }
+}
+
+/// link every path of replaces to the first of keeps with link, moving them into keeps
+/// reports links and failures to on_event, and records errors in errors
+fn hardlink_all<'a>(
+    keeps: &mut InodeGroup<'a>,
+    replaces: &InodeGroup<'a>,
+    cfg: &Config,
+    errors: &mut ErrorTally,
+    on_event: OnEvent,
+    link: impl Fn(&PathWithMetadata, &PathWithMetadata) -> Result<Option<u64>, Error>,
+) -> Outcome<'a> {
+    let replaced_md = replaces.first().unwrap().md().clone();
+    if let Some(journal) = &cfg.journal && cfg.link_mode == LinkMode::Hardlink && !cfg.dry_run {
+        let keep = keeps.first().unwrap();
+        let journaled = replaces.iter()
+            .try_for_each(|replace| journal.append(&keep.path, &replace.path, &replaced_md))
+            .and_then(|_| journal.sync());
+        if let Err(error) = journaled { // linking without a record could not be undone
+            for replace in replaces {
+                on_event(Event::LinkFailed {
+                    size: replaced_md.st_size(),
+                    keep: &keep.path,
+                    replace: &replace.path,
+                    error: &error,
+                });
+                errors.record(&error);
+            }
+            return Outcome::Failed;
+        }
+    }
+    let mut rest: Option<InodeGroup> = None;
+    let mut failed = false;
+    for (i, replace) in replaces.iter().enumerate() {
+        let keep = keeps.first().unwrap();
+        let mut bytes_deduped = None;
+        if !cfg.dry_run {
+            let keep_nlink = keep.md().st_nlink();
+            // linking earlier paths of replaces changed the ctime of their inode
+            let result = keep.check_unchanged(true)
+                .and_then(|_| replace.check_unchanged(i == 0))
+                .and_then(|_| link(keep, replace))
+                .and_then(|n| {
+                    bytes_deduped = n;
+                    match cfg.verify {
+                        true => verify_link(keep, replace, keep_nlink, &replaced_md, cfg.link_mode),
+                        false => Ok(()),
+                    }
+                });
+            if let Err(Error::TooManyLinks { .. }) = &result {
+                on_event(Event::LinkLimitReached {
+                    size: replaced_md.st_size(),
+                    keep: &keep.path,
+                    new_keep: &replace.path,
+                });
+                let _ = replace.reset_md(); // linking earlier paths of replaces changed its link count and ctime
+                rest = Some(replaces[i..].iter().copied().collect());
+                break;
+            }
+            if let Err(error) = result {
+                on_event(Event::LinkFailed {
+                    size: replaced_md.st_size(),
+                    keep: &keep.path,
+                    replace: &replace.path,
+                    error: &error,
+                });
+                errors.record(&error);
+                failed = true;
+                continue; // replace was not linked, or not verifiably
+            }
+        }
+        on_event(Event::Linked {
+            size: replaced_md.st_size(),
+            keep: &keep.path,
+            replace: &replace.path,
+            bytes_deduped,
+        });
+        if let Some(progress) = &cfg.progress {
+            progress.linked();
+        }
+        keeps.push(replace);
+    }
+    if let Some(cache) = &cfg.hash_cache && !cfg.dry_run {
+        match &rest {
+            Some(rest) => cache.restamp(&rest[0].md()),
+            None if cfg.link_mode == LinkMode::Hardlink => cache.forget(&replaced_md),
+            None => {}, // reflinked and deduped inodes remain, with the same contents
+        }
+        // linking changed the ctime of the kept inode, or of the inode reflinked or deduped
+        cache.restamp(&keeps.last().unwrap().md());
+    }
+    match rest {
+        Some(rest) => Outcome::LimitReached(rest),
+        None if failed => Outcome::Failed,
+        None => Outcome::Linked,
+    }
+}
+
+
+pub const PARTIAL_HASH_BLOCK: u64 = 4096;
+
+/// hash of the first and last PARTIAL_HASH_BLOCK bytes of a file of size fsize
+/// path is opened as by open_regular
+pub fn hash_partial(path: impl AsRef<Path>, fsize: u64) -> std::io::Result<u64> {
+    use std::io::{Seek, SeekFrom};
+    let mut f = open_regular(path.as_ref())?;
+    let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+    let buff: &mut [u8] = &mut [0; PARTIAL_HASH_BLOCK as usize];
+    let head = std::cmp::min(fsize, PARTIAL_HASH_BLOCK) as usize;
+    f.read_exact(&mut buff[..head])?;
+    hasher.update(&buff[..head]);
+    if fsize > PARTIAL_HASH_BLOCK {
+        let tail_start = std::cmp::max(PARTIAL_HASH_BLOCK, fsize - PARTIAL_HASH_BLOCK);
+        let tail = (fsize - tail_start) as usize;
+        f.seek(SeekFrom::Start(tail_start))?;
+        f.read_exact(&mut buff[..tail])?;
+        hasher.update(&buff[..tail]);
+    }
+    Ok(hasher.digest())
+}
+
+/// hash of the entire contents of a file
+/// path is opened as by open_regular
+pub fn hash_full(path: impl AsRef<Path>) -> std::io::Result<u128> {
+    let mut f = open_regular(path.as_ref())?;
+    let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+    let buff: &mut [u8] = &mut [0; 65536];
+    loop {
+        let l = f.read(buff)?;
+        if l == 0 {
+            return Ok(hasher.digest128());
+        }
+        hasher.update(&buff[..l]);
+    }
+}
+
+
+/// check equality of contents of two paths to files, opened as by open_regular
+/// does not check sizes
+pub fn cmp(f1: impl AsRef<Path>, f2: impl AsRef<Path>) -> Result<bool, Error> {
+    let (f1, f2) = (f1.as_ref(), f2.as_ref());
+    let open = |path| open_regular(path).map_err(|e| Error::io(Operation::Read, path, e));
+    cmp_read(open(f1)?, open(f2)?)
+        .map_err(|(first, e)| Error::io(Operation::Read, if first { f1 } else { f2 }, e))
+}
+
+/// check equality of contents of two open files
+/// on failure, also returns whether it was reading the first that failed
+pub fn cmp_read(mut f1: impl Read, mut f2: impl Read) -> Result<bool, (bool, std::io::Error)> {
+    let buff1: &mut [u8] = &mut [0; 1024];
+    let buff2: &mut [u8] = &mut [0; 1024];
+    loop {
+        let l1 = f1.read(buff1).map_err(|e| (true, e))?;
+        let l2 = f2.read(buff2).map_err(|e| (false, e))?;
+        if l1 != l2 { // different sizes
+            return Ok(false);
+        }
+        if l1 == 0 { // end of both files
+            return Ok(true);
+        }
+        if buff1[0..l1] != buff2[0..l2] { // compare data
+            return Ok(false);
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::fs::metadata_nofollow;
+    #[test]
+    pub fn test_hash_partial_ignores_middle() {
+        let dir = TempDir::new("hash");
+        let size = 3 * PARTIAL_HASH_BLOCK as usize;
+        let mut contents = vec![7u8; size];
+        std::fs::write(dir.join("a"), &contents).unwrap();
+        contents[size / 2] = 8;
+        std::fs::write(dir.join("b"), &contents).unwrap();
+        assert_eq!(hash_partial(dir.join("a"), size as u64).unwrap(), hash_partial(dir.join("b"), size as u64).unwrap());
+        assert_ne!(hash_full(dir.join("a")).unwrap(), hash_full(dir.join("b")).unwrap());
+    }
+    #[test]
+    pub fn test_keeper_index() {
+        let dir = TempDir::new("keeper");
+        let mtime = |secs| std::fs::FileTimes::new().set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+        for (name, secs) in [("a", 200), ("b", 100), ("c", 300), ("d", 100)] {
+            std::fs::write(dir.join(name), b"contents").unwrap();
+            std::fs::File::options().write(true).open(dir.join(name)).unwrap().set_times(mtime(secs)).unwrap();
+        }
+        std::fs::hard_link(dir.join("c"), dir.join("outside")).unwrap(); // not among the targets
+        let pwmd = |name: &str, target| {
+            let mut pwmd = PathWithMetadata::new(dir.join(name)).unwrap();
+            pwmd.target = target;
+            pwmd
+        };
+        let (a, b, c, d) = (pwmd("a", 2), pwmd("b", 1), pwmd("c", 1), pwmd("d", 3));
+        let class: Vec<InodeGroup> = vec![smallvec![&a], smallvec![&b], smallvec![&c], smallvec![&d]];
+        assert_eq!(keeper_index(&class, KeepPolicy::MostLinks), 2); // most hardlinks overall
+        assert_eq!(keeper_index(&class, KeepPolicy::OldestMtime), 1); // b and d tie; earliest wins
+        assert_eq!(keeper_index(&class, KeepPolicy::NewestMtime), 2);
+        assert_eq!(keeper_index(&class, KeepPolicy::PathPriority), 1); // b and c tie; earliest wins
+        let lowest = (0..class.len()).min_by_key(|&i| class[i][0].md().st_ino()).unwrap();
+        assert_eq!(keeper_index(&class, KeepPolicy::LowestInode), lowest);
+
+        // more paths among the targets outweigh more hardlinks overall
+        let a2 = pwmd("a", 0);
+        let class: Vec<InodeGroup> = vec![smallvec![&b], smallvec![&c], smallvec![&a, &a2]];
+        assert_eq!(keeper_index(&class, KeepPolicy::MostLinks), 2);
+        assert_eq!(keeper_index(&class, KeepPolicy::PathPriority), 2); // lowest target of any path
+        // a full tie keeps the first
+        let class: Vec<InodeGroup> = vec![smallvec![&b], smallvec![&d]];
+        assert_eq!(keeper_index(&class, KeepPolicy::MostLinks), 0);
+    }
+    #[test]
+    pub fn test_hardlink_all_link_limit() {
+        let dir = TempDir::new("link-limit");
+        for name in ["k", "r", "s"] {
+            std::fs::write(dir.join(name), b"contents").unwrap();
+        }
+        for name in ["r2", "r3"] {
+            std::fs::hard_link(dir.join("r"), dir.join(name)).unwrap();
+        }
+        let cache = crate::cache::HashCache::load(dir.join("cache")).unwrap();
+        let pwmds: Vec<_> = ["k", "r", "r2", "r3", "s"].iter()
+            .map(|name| PathWithMetadata::new(dir.join(name)).unwrap())
+            .collect();
+        for pwmd in &pwmds {
+            cache.full(&pwmd.md(), || Ok(1)).unwrap();
+        }
+        let [k, r, r2, r3, s] = &pwmds[..] else { unreachable!() };
+        let cfg = Config::builder().verify(true).hash_cache(Some(cache)).build();
+        let limit_events = std::sync::atomic::AtomicUsize::new(0);
+        let on_event = |event: Event| if let Event::LinkLimitReached { .. } = event {
+            limit_events.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        };
+        let mut errors = ErrorTally::default();
+        // the kept inode takes a single link more
+        let links = std::sync::atomic::AtomicUsize::new(0);
+        let limited = |keep: &PathWithMetadata, replace: &PathWithMetadata| {
+            match links.fetch_add(1, std::sync::atomic::Ordering::Relaxed) {
+                0 => hardlink(keep, replace).map(|_| None),
+                _ => Err(Error::TooManyLinks { path: keep.path.clone() }),
+            }
+        };
+
+        // limit reached partway through replaces
+        let mut keeps: InodeGroup = smallvec![k];
+        let Outcome::LimitReached(rest) = hardlink_all(&mut keeps, &smallvec![r, r2, r3], &cfg, &mut errors, &on_event, limited)
+            else { panic!("link limit not reached") };
+        assert!(errors.is_empty());
+        assert_eq!(limit_events.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(keeps.iter().map(|pwmd| pwmd.path.clone()).collect::<Vec<_>>(), vec![k.path.clone(), r.path.clone()]);
+        assert_eq!(rest.iter().map(|pwmd| pwmd.path.clone()).collect::<Vec<_>>(), vec![r2.path.clone(), r3.path.clone()]);
+        // rest[0] was restamped: its link count and ctime changed when r was linked away
+        let rest_md = metadata_nofollow(&rest[0].path).unwrap();
+        assert_eq!(rest[0].md().st_nlink(), 2);
+        assert_eq!((rest[0].md().st_ctime(), rest[0].md().st_ctime_nsec()), (rest_md.st_ctime(), rest_md.st_ctime_nsec()));
+        let cache = cfg.hash_cache().unwrap();
+        assert_eq!(cache.full(&rest[0].md(), || panic!("not restamped")).unwrap(), 1);
+        assert_eq!(cache.full(&k.md(), || panic!("not restamped")).unwrap(), 1);
+
+        // the remaining duplicates are linked to the new kept inode
+        let mut keeps = rest;
+        let link = |keep: &PathWithMetadata, replace: &PathWithMetadata| share_storage(keep, replace, LinkMode::Hardlink);
+        assert!(matches!(hardlink_all(&mut keeps, &smallvec![s], &cfg, &mut errors, &on_event, link), Outcome::Linked));
+        assert!(errors.is_empty());
+        assert_eq!(metadata_nofollow(&s.path).unwrap().st_ino(), metadata_nofollow(&r2.path).unwrap().st_ino());
+
+        // limit reached at the first path: nothing is linked and all of replaces remains
+        let mut keeps: InodeGroup = smallvec![k];
+        let Outcome::LimitReached(rest) = hardlink_all(&mut keeps, &smallvec![r2, r3, s], &cfg, &mut errors, &on_event, limited)
+            else { panic!("link limit not reached") };
+        assert_eq!((keeps.len(), rest.len()), (1, 3));
+        assert_eq!(metadata_nofollow(&k.path).unwrap().st_nlink(), 2);
+    }
+    #[test]
+    pub fn test_hardlink_all_refuses_changed_files() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("changed");
+        for name in ["k", "modified", "resized", "chmodded", "p1"] {
+            std::fs::write(dir.join(name), b"contents").unwrap();
+        }
+        std::fs::hard_link(dir.join("p1"), dir.join("p2")).unwrap();
+        let pwmds: Vec<_> = ["k", "modified", "resized", "chmodded", "p1", "p2"].iter()
+            .map(|name| PathWithMetadata::new(dir.join(name)).unwrap())
+            .collect();
+        let [k, modified, resized, chmodded, p1, p2] = &pwmds[..] else { unreachable!() };
+        std::thread::sleep(std::time::Duration::from_millis(20)); // ctime may be coarser than a nanosecond
+        std::fs::File::options().write(true).open(dir.join("modified")).unwrap()
+            .set_modified(std::time::UNIX_EPOCH).unwrap();
+        std::fs::write(dir.join("resized"), b"longer contents").unwrap();
+        std::fs::set_permissions(dir.join("chmodded"), std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let reasons = std::sync::Mutex::new(Vec::new());
+        let on_event = |event: Event| if let Event::LinkFailed { error: Error::Changed { reason, .. }, .. } = event {
+            reasons.lock().unwrap().push(*reason);
+        };
+        let cfg = Config::builder().build();
+        let mut errors = ErrorTally::default();
+        let link = |keep: &PathWithMetadata, replace: &PathWithMetadata| share_storage(keep, replace, LinkMode::Hardlink);
+        let mut keeps: InodeGroup = smallvec![k];
+        for replace in [modified, resized, chmodded] {
+            let ino = replace.md().st_ino();
+            assert!(matches!(hardlink_all(&mut keeps, &smallvec![replace], &cfg, &mut errors, &on_event, link), Outcome::Failed));
+            let md = metadata_nofollow(&replace.path).unwrap();
+            assert_eq!((md.st_ino(), md.st_nlink()), (ino, 1)); // left untouched
+        }
+        assert_eq!(*reasons.lock().unwrap(), vec!["was modified", "changed size", "changed metadata"]);
+        assert_eq!(errors.total(), 3);
+
+        // linking p1 changes the ctime of the inode p2 shares, which must not count as a change
+        assert!(matches!(hardlink_all(&mut keeps, &smallvec![p1, p2], &cfg, &mut errors, &on_event, link), Outcome::Linked));
+        assert_eq!(errors.total(), 3);
+        assert_eq!(metadata_nofollow(&k.path).unwrap().st_nlink(), 3);
+    }
+    #[test]
+    pub fn test_hardlink_all_keeps_cache_of_reflinked_inode() {
+        let dir = TempDir::new("reflink-cache");
+        for name in ["k", "r"] {
+            std::fs::write(dir.join(name), b"contents").unwrap();
+        }
+        let cache = crate::cache::HashCache::load(dir.join("cache")).unwrap();
+        let (k, r) = (PathWithMetadata::new(dir.join("k")).unwrap(), PathWithMetadata::new(dir.join("r")).unwrap());
+        cache.full(&r.md(), || Ok(1)).unwrap();
+        let cfg = Config::builder().link_mode(LinkMode::Reflink).hash_cache(Some(cache)).build();
+        std::thread::sleep(std::time::Duration::from_millis(20)); // ctime may be coarser than a nanosecond
+        // stands in for FICLONE, which changes the ctime of the destination
+        let reflink = |_: &PathWithMetadata, replace: &PathWithMetadata| {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(replace.md().st_mode());
+            std::fs::set_permissions(&replace.path, permissions).unwrap();
+            replace.reset_md().map(|_| None)
+        };
+        let mut errors = ErrorTally::default();
+        let outcome = hardlink_all(&mut smallvec![&k], &smallvec![&r], &cfg, &mut errors, &|_| {}, reflink);
+        assert!(matches!(outcome, Outcome::Linked));
+        assert_eq!(cfg.hash_cache().unwrap().full(&r.md(), || panic!("forgotten")).unwrap(), 1);
+    }
+    #[test]
+    pub fn test_split_by_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("split-metadata");
+        let names = ["a", "b", "c", "d", "e"];
+        for name in names {
+            std::fs::write(dir.join(name), b"contents").unwrap();
+        }
+        for name in ["c", "d"] {
+            std::fs::set_permissions(dir.join(name), std::fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        let is_root = unsafe { libc::geteuid() } == 0; // only root can give files away
+        if is_root {
+            std::os::unix::fs::lchown(dir.join("b"), Some(1), None).unwrap();
+        }
+        let set_xattr = |name: &str| {
+            let path = std::ffi::CString::new(dir.join(name).into_os_string().into_encoded_bytes()).unwrap();
+            unsafe { libc::setxattr(path.as_ptr(), c"user.lndups-test".as_ptr(), b"1".as_ptr() as *const libc::c_void, 1, 0) == 0 }
+        };
+        let has_xattrs = set_xattr("d") && set_xattr("e"); // not every filesystem supports user xattrs
+        let pwmds: Vec<_> = names.iter().map(|name| PathWithMetadata::new(dir.join(name)).unwrap()).collect();
+        let split = |cfg: Config, errors: &mut ErrorTally| {
+            let class: Vec<InodeGroup> = pwmds.iter().map(|pwmd| smallvec![pwmd]).collect();
+            split_by_metadata(class, &cfg, errors, &|_| {}).into_iter()
+                .map(|class| class.iter().map(|pwmds| pwmds[0].path.file_name().unwrap().to_str().unwrap()).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        let mut errors = ErrorTally::default();
+        assert_eq!(split(Config::builder().build(), &mut errors), vec![names.to_vec()]);
+        assert_eq!(split(Config::builder().require_same_mode(true).build(), &mut errors), vec![vec!["a", "b", "e"], vec!["c", "d"]]);
+        if is_root {
+            assert_eq!(split(Config::builder().require_same_owner(true).build(), &mut errors), vec![vec!["a", "c", "d", "e"]]);
+        }
+        if has_xattrs {
+            assert_eq!(split(Config::builder().require_same_xattrs(true).build(), &mut errors), vec![vec!["a", "b", "c"], vec!["d", "e"]]);
+        }
+        assert!(errors.is_empty());
+
+        std::fs::remove_file(dir.join("a")).unwrap(); // xattrs unreadable after hashing
+        let fifo = std::ffi::CString::new(dir.join("a").into_os_string().into_encoded_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        let classes = split(Config::builder().require_same_xattrs(true).build(), &mut errors);
+        assert!(classes.iter().flatten().all(|&name| name != "a"));
+        assert_eq!(errors.total(), 1);
+    }
+    #[test]
+    pub fn test_partition_identical_reports_errors() {
+        let dir = TempDir::new("partition");
+        for name in ["a", "b", "c"] {
+            std::fs::write(dir.join(name), b"contents").unwrap();
+        }
+        let pwmds: Vec<_> = ["a", "b", "c"].iter().map(|name| PathWithMetadata::new(dir.join(name)).unwrap()).collect();
+        std::fs::remove_file(dir.join("c")).unwrap(); // unreadable after hashing
+        let fifo = std::ffi::CString::new(dir.join("c").into_os_string().into_encoded_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        let reported = std::sync::Mutex::new(Vec::new());
+        let on_event = |event: Event| if let Event::Error(error) = event {
+            reported.lock().unwrap().push(error.path().unwrap().to_path_buf());
+        };
+        let mut errors = ErrorTally::default();
+        let group: Vec<InodeGroup> = pwmds.iter().map(|pwmd| smallvec![pwmd]).collect();
+        let classes = partition_identical(group, &Config::builder().build(), &mut errors, &on_event);
+        assert_eq!(classes.len(), 1);
+        assert_eq!(classes[0].len(), 2);
+        assert_eq!(errors.total(), 1);
+        assert_eq!(*reported.lock().unwrap(), vec![dir.join("c")]);
+    }
+    #[test]
+    pub fn test_hash_refuses_fifo_and_symlink() {
+        let dir = TempDir::new("hash-special");
+        std::fs::write(dir.join("file"), b"contents").unwrap();
+        std::os::unix::fs::symlink(dir.join("file"), dir.join("symlink")).unwrap();
+        let fifo = std::ffi::CString::new(dir.join("fifo").into_os_string().into_encoded_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        assert!(hash_full(dir.join("fifo")).is_err());
+        assert!(hash_partial(dir.join("symlink"), 8).is_err());
+        let error = cmp(dir.join("file"), dir.join("fifo")).unwrap_err();
+        assert_eq!(error.path(), Some(dir.join("fifo").as_path()));
+        assert!(hash_full(dir.join("file")).is_ok());
+    }
+}
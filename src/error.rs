@@ -0,0 +1,226 @@
+use std::path::{Path, PathBuf};
+
+
+
+/// operation on a path that failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Stat,
+    Canonicalize,
+    ReadDir,
+    Open,
+    Create,
+    Read,
+    Write,
+    Remove,
+    Link,
+    Rename,
+    Copy,
+    Reflink,
+    Dedupe,
+    ReadXattrs,
+    SetOwner,
+    SetPermissions,
+    SetTimes,
+}
+impl Operation {
+    /// identifier used in JSON output
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Stat => "stat",
+            Operation::Canonicalize => "canonicalize",
+            Operation::ReadDir => "read_dir",
+            Operation::Open => "open",
+            Operation::Create => "create",
+            Operation::Read => "read",
+            Operation::Write => "write",
+            Operation::Remove => "remove",
+            Operation::Link => "link",
+            Operation::Rename => "rename",
+            Operation::Copy => "copy",
+            Operation::Reflink => "reflink",
+            Operation::Dedupe => "dedupe",
+            Operation::ReadXattrs => "read_xattrs",
+            Operation::SetOwner => "set_owner",
+            Operation::SetPermissions => "set_permissions",
+            Operation::SetTimes => "set_times",
+        }
+    }
+    /// completes "Failed to "
+    fn phrase(self) -> &'static str {
+        match self {
+            Operation::Stat => "retrieve metadata for",
+            Operation::Canonicalize => "retrieve absolute path for",
+            Operation::ReadDir => "read dir",
+            Operation::Open => "open",
+            Operation::Create => "create",
+            Operation::Read => "read",
+            Operation::Write => "write",
+            Operation::Remove => "remove",
+            Operation::Link => "hardlink",
+            Operation::Rename => "rename",
+            Operation::Copy => "copy",
+            Operation::Reflink => "reflink",
+            Operation::Dedupe => "dedupe",
+            Operation::ReadXattrs => "read extended attributes of",
+            Operation::SetOwner => "restore owner of",
+            Operation::SetPermissions => "restore permissions of",
+            Operation::SetTimes => "restore timestamps of",
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// an operation on path failed
+    Io { operation: Operation, path: PathBuf, source: std::io::Error },
+    /// path no longer refers to the file described by the metadata from the scan
+    Changed { path: PathBuf, reason: &'static str },
+    /// the inode of path already has the maximum number of links the filesystem allows
+    TooManyLinks { path: PathBuf },
+    /// only the first `deduped` of `size` bytes of path were deduped
+    PartiallyDeduped { path: PathBuf, deduped: u64, size: u64, differs: bool },
+    /// path does not share storage as expected after linking
+    Unverified { path: PathBuf, reason: &'static str },
+    /// contents of path are not in the expected format
+    Malformed { path: PathBuf, reason: &'static str },
+    /// invalid arguments
+    Usage(String),
+}
+impl Error {
+    pub fn io(operation: Operation, path: impl Into<PathBuf>, source: std::io::Error) -> Self {
+        Error::Io { operation, path: path.into(), source }
+    }
+    /// path the error concerns
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::Io { path, .. }
+            | Error::Changed { path, .. }
+            | Error::TooManyLinks { path }
+            | Error::PartiallyDeduped { path, .. }
+            | Error::Unverified { path, .. }
+            | Error::Malformed { path, .. } => Some(path),
+            Error::Usage(_) => None,
+        }
+    }
+    /// identifier used to tally errors; the operation for io errors
+    pub fn category(&self) -> &'static str {
+        match self {
+            Error::Io { operation, .. } => operation.name(),
+            Error::Changed { .. } => "changed",
+            Error::TooManyLinks { .. } => "too_many_links",
+            Error::PartiallyDeduped { .. } => "partially_deduped",
+            Error::Unverified { .. } => "unverified",
+            Error::Malformed { .. } => "malformed",
+            Error::Usage(_) => "usage",
+        }
+    }
+    pub fn operation(&self) -> Option<Operation> {
+        match self {
+            Error::Io { operation, .. } => Some(*operation),
+            _ => None,
+        }
+    }
+    /// errno of the underlying io error
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Error::Io { source, .. } => source.raw_os_error(),
+            Error::TooManyLinks { .. } => Some(libc::EMLINK),
+            _ => None,
+        }
+    }
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let quote = |path: &Path| shlex::try_quote(&path.to_string_lossy()).unwrap().into_owned();
+        match self {
+            Error::Io { operation, path, source } =>
+                write!(f, "Failed to {} {}: {}", operation.phrase(), quote(path), source),
+            Error::Changed { path, reason } =>
+                write!(f, "Skipped, {} {} since scan", quote(path), reason),
+            Error::TooManyLinks { path } =>
+                write!(f, "Failed to hardlink {}: too many links", quote(path)),
+            Error::PartiallyDeduped { path, deduped, size, differs: true } =>
+                write!(f, "Contents differ, deduped {}/{} bytes of {}", deduped, size, quote(path)),
+            Error::PartiallyDeduped { path, deduped, size, differs: false } =>
+                write!(f, "Partially deduped {}/{} bytes of {}", deduped, size, quote(path)),
+            Error::Unverified { path, reason } =>
+                write!(f, "Verification failed: {} {}", quote(path), reason),
+            Error::Malformed { path, reason } =>
+                write!(f, "{}: {}", reason, quote(path)),
+            Error::Usage(msg) => f.write_str(msg),
+        }
+    }
+}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+
+
+/// number of errors by category
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ErrorTally(std::collections::BTreeMap<&'static str, u64>);
+impl ErrorTally {
+    pub fn record(&mut self, error: &Error) {
+        *self.0.entry(error.category()).or_default() += 1;
+    }
+    pub fn total(&self) -> u64 {
+        self.0.values().sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// categories and their counts, in alphabetical order
+    pub fn iter(&self) -> impl Iterator<Item=(&'static str, u64)> + '_ {
+        self.0.iter().map(|(category, count)| (*category, *count))
+    }
+}
+impl std::ops::AddAssign for ErrorTally {
+    fn add_assign(&mut self, other: ErrorTally) {
+        for (category, count) in other.0 {
+            *self.0.entry(category).or_default() += count;
+        }
+    }
+}
+impl std::fmt::Display for ErrorTally {
+    /// ex: 2 link, 1 stat
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, (category, count)) in self.iter().enumerate() {
+            write!(f, "{}{} {}", if i == 0 { "" } else { ", " }, count, category)?;
+        }
+        Ok(())
+    }
+}
+
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    #[test]
+    pub fn test_io_error() {
+        let error = Error::io(Operation::Link, "/tmp/a b", std::io::Error::from_raw_os_error(libc::EXDEV));
+        assert_eq!(error.operation().map(Operation::name), Some("link"));
+        assert_eq!(error.raw_os_error(), Some(libc::EXDEV));
+        assert_eq!(error.path(), Some(Path::new("/tmp/a b")));
+        assert!(error.to_string().starts_with("Failed to hardlink '/tmp/a b': "));
+    }
+    #[test]
+    pub fn test_error_tally() {
+        let mut tally = ErrorTally::default();
+        tally.record(&Error::io(Operation::Stat, "/a", std::io::Error::from_raw_os_error(libc::ENOENT)));
+        let mut other = ErrorTally::default();
+        other.record(&Error::Changed { path: "/b".into(), reason: "was modified" });
+        other.record(&Error::io(Operation::Stat, "/c", std::io::Error::from_raw_os_error(libc::EACCES)));
+        tally += other;
+        assert_eq!(tally.total(), 3);
+        assert_eq!(tally.to_string(), "1 changed, 2 stat");
+    }
+}
@@ -0,0 +1,37 @@
+use std::path::Path;
+
+use crate::error::Error;
+
+
+
+/// something that happened during scan, deduplicate or undo, for the caller to report
+/// handlers are called from the worker threads, as it happens
+#[derive(Clone, Copy, Debug)]
+pub enum Event<'a> {
+    /// an error that did not stop the run; also tallied in its summary
+    Error(&'a Error),
+    /// a directory on another device than its target, not descended into
+    SkippedMountPoint(&'a Path),
+    /// a set of identical files of size, by inode; the paths of the inode kept come first
+    GroupFound { size: u64, inodes: usize, paths: &'a [&'a Path] },
+    /// replace was made to share storage with keep, or would have been in a dry run
+    /// bytes_deduped is known for LinkMode::DedupeRange
+    Linked { size: u64, keep: &'a Path, replace: &'a Path, bytes_deduped: Option<u64> },
+    /// replace was not linked to keep, or not verifiably; also tallied in the summary
+    LinkFailed { size: u64, keep: &'a Path, replace: &'a Path, error: &'a Error },
+    /// keep reached the link limit, so the inode of new_keep is kept for the remaining duplicates
+    LinkLimitReached { size: u64, keep: &'a Path, new_keep: &'a Path },
+    /// all files of size were processed; counted as in Summary
+    SizeDone { size: u64, inodes_starting: usize, inodes_linked: usize },
+    /// a line of a journal could not be parsed and was ignored; counted from 1
+    MalformedJournalLine { journal: &'a Path, line: usize },
+    /// replace was split from keep, or would have been in a dry run
+    Unlinked { keep: &'a Path, replace: &'a Path },
+    /// replace is no longer linked to keep, so it was left alone
+    NotLinked { keep: &'a Path, replace: &'a Path },
+    /// replace could not be split from keep; also tallied in the summary
+    UnlinkFailed { keep: &'a Path, replace: &'a Path, error: &'a Error },
+}
+
+/// receives events; must be callable from several threads at once
+pub type OnEvent<'a> = &'a (dyn Fn(Event) + Sync);
@@ -0,0 +1,339 @@
+use std::path::Path;
+
+
+
+/// metadata of a file as returned by fstatat or fstat
+/// unlike std::fs::Metadata, obtainable for a name relative to a directory without opening it
+#[derive(Clone)]
+pub struct Metadata(libc::stat);
+// the types of the fields of libc::stat vary by architecture
+#[allow(clippy::unnecessary_cast)]
+impl Metadata {
+    pub fn st_dev(&self) -> u64 { self.0.st_dev as u64 }
+    pub fn st_ino(&self) -> u64 { self.0.st_ino as u64 }
+    pub fn st_mode(&self) -> u32 { self.0.st_mode as u32 }
+    pub fn st_nlink(&self) -> u64 { self.0.st_nlink as u64 }
+    pub fn st_uid(&self) -> u32 { self.0.st_uid as u32 }
+    pub fn st_gid(&self) -> u32 { self.0.st_gid as u32 }
+    pub fn st_size(&self) -> u64 { self.0.st_size as u64 }
+    pub fn st_atime(&self) -> i64 { self.0.st_atime as i64 }
+    pub fn st_atime_nsec(&self) -> i64 { self.0.st_atime_nsec as i64 }
+    pub fn st_mtime(&self) -> i64 { self.0.st_mtime as i64 }
+    pub fn st_mtime_nsec(&self) -> i64 { self.0.st_mtime_nsec as i64 }
+    pub fn st_ctime(&self) -> i64 { self.0.st_ctime as i64 }
+    pub fn st_ctime_nsec(&self) -> i64 { self.0.st_ctime_nsec as i64 }
+    pub fn is_file(&self) -> bool { self.file_type() == libc::S_IFREG }
+    pub fn is_dir(&self) -> bool { self.file_type() == libc::S_IFDIR }
+    pub fn is_symlink(&self) -> bool { self.file_type() == libc::S_IFLNK }
+    pub fn accessed(&self) -> std::time::SystemTime { system_time(self.st_atime(), self.st_atime_nsec()) }
+    pub fn modified(&self) -> std::time::SystemTime { system_time(self.st_mtime(), self.st_mtime_nsec()) }
+    fn file_type(&self) -> libc::mode_t {
+        self.0.st_mode & libc::S_IFMT
+    }
+}
+impl std::fmt::Debug for Metadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Metadata")
+            .field("dev", &self.st_dev())
+            .field("ino", &self.st_ino())
+            .field("mode", &format_args!("{:o}", self.st_mode()))
+            .field("size", &self.st_size())
+            .finish_non_exhaustive()
+    }
+}
+
+fn system_time(sec: i64, nsec: i64) -> std::time::SystemTime {
+    let since_epoch = |sec: i64| std::time::Duration::new(sec.unsigned_abs(), 0);
+    let whole = match sec >= 0 {
+        true => std::time::UNIX_EPOCH + since_epoch(sec),
+        false => std::time::UNIX_EPOCH - since_epoch(sec),
+    };
+    whole + std::time::Duration::from_nanos(nsec as u64)
+}
+
+/// metadata of name relative to dir (fstatat), not following a symlink as name
+pub fn stat_at(dir: std::os::fd::BorrowedFd, name: &std::ffi::OsStr) -> std::io::Result<Metadata> {
+    use std::os::fd::AsRawFd;
+    let name = c_name(name)?;
+    let mut buf = std::mem::MaybeUninit::<libc::stat>::uninit();
+    check_errno(unsafe { libc::fstatat(dir.as_raw_fd(), name.as_ptr(), buf.as_mut_ptr(), libc::AT_SYMLINK_NOFOLLOW) })?;
+    Ok(Metadata(unsafe { buf.assume_init() }))
+}
+
+/// metadata of the file open as fd (fstat)
+pub fn stat_fd(fd: std::os::fd::BorrowedFd) -> std::io::Result<Metadata> {
+    use std::os::fd::AsRawFd;
+    let mut buf = std::mem::MaybeUninit::<libc::stat>::uninit();
+    check_errno(unsafe { libc::fstat(fd.as_raw_fd(), buf.as_mut_ptr()) })?;
+    Ok(Metadata(unsafe { buf.assume_init() }))
+}
+
+/// extended attributes of the regular file at absolute path, opened as by open_regular, sorted by name
+/// includes POSIX ACLs, which are stored as system.posix_acl_access and system.posix_acl_default
+pub fn xattrs(path: &Path) -> std::io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    use std::os::fd::AsRawFd;
+    let f = open_regular(path)?;
+    let names = read_sized(|buf, size| unsafe {
+        libc::flistxattr(f.as_raw_fd(), buf as *mut libc::c_char, size)
+    })?;
+    let mut result = Vec::new();
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let cname = std::ffi::CString::new(name)?;
+        let value = read_sized(|buf, size| unsafe {
+            libc::fgetxattr(f.as_raw_fd(), cname.as_ptr(), buf as *mut libc::c_void, size)
+        })?;
+        result.push((name.to_vec(), value));
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// call a syscall wrapper that fills a buffer, retrying while the buffer is too small
+/// f(null, 0) must return the required size
+fn read_sized(f: impl Fn(*mut u8, usize) -> isize) -> std::io::Result<Vec<u8>> {
+    loop {
+        let size = f(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let len = f(buf.as_mut_ptr(), buf.len());
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::ERANGE) { // grew in between
+            return Err(error);
+        }
+    }
+}
+
+/// open name relative to dir, never following a symlink as its last component
+pub fn open_at(
+    dir: std::os::fd::BorrowedFd,
+    name: &std::ffi::OsStr,
+    flags: libc::c_int,
+    mode: libc::mode_t,
+) -> std::io::Result<std::os::fd::OwnedFd> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+    let name = c_name(name)?;
+    let fd = unsafe {
+        libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_NOFOLLOW | libc::O_CLOEXEC, mode as libc::c_uint)
+    };
+    match fd < 0 {
+        true => Err(std::io::Error::last_os_error()),
+        false => Ok(unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+/// open the directory at absolute path without following symlinks in any of its components
+/// path must not contain . or .. components
+pub fn open_dir(path: &Path, flags: libc::c_int) -> std::io::Result<std::os::fd::OwnedFd> {
+    use std::os::fd::AsFd;
+    use std::path::Component;
+    check_canonical(path)?;
+    if let Some(result) = open_no_symlinks(path, flags | libc::O_DIRECTORY) {
+        return result;
+    }
+    let mut dir = std::os::fd::OwnedFd::from(std::fs::File::open("/")?);
+    for component in path.components() {
+        if let Component::Normal(name) = component {
+            dir = open_at(dir.as_fd(), name, libc::O_PATH | libc::O_DIRECTORY, 0)?;
+        }
+    }
+    open_at(dir.as_fd(), std::ffi::OsStr::new("."), flags | libc::O_DIRECTORY, 0)
+}
+
+/// open the parent directory of absolute path as by open_dir, returning it with the file name of path
+pub fn open_parent(path: &Path) -> std::io::Result<(std::os::fd::OwnedFd, &std::ffi::OsStr)> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok((open_dir(parent, libc::O_PATH)?, name)),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "path has no parent directory")),
+    }
+}
+
+/// open absolute path without following symlinks in any of its components
+/// path must not contain . or .. components
+pub fn open_nofollow(path: &Path, flags: libc::c_int) -> std::io::Result<std::os::fd::OwnedFd> {
+    use std::os::fd::AsFd;
+    check_canonical(path)?;
+    if let Some(result) = open_no_symlinks(path, flags | libc::O_NOFOLLOW) {
+        return result;
+    }
+    match path.file_name() {
+        Some(_) => {
+            let (dir, name) = open_parent(path)?;
+            open_at(dir.as_fd(), name, flags, 0)
+        },
+        None => open_dir(path, flags), // root
+    }
+}
+
+/// open absolute path in one syscall, failing on a symlink in any component (openat2 with RESOLVE_NO_SYMLINKS)
+/// None if the kernel lacks openat2, which came with Linux 5.6
+fn open_no_symlinks(path: &Path, flags: libc::c_int) -> Option<std::io::Result<std::os::fd::OwnedFd>> {
+    use std::os::fd::FromRawFd;
+    let path = match c_name(path.as_os_str()) {
+        Ok(path) => path,
+        Err(error) => return Some(Err(error)),
+    };
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() }; // non-exhaustive
+    how.flags = (flags | libc::O_CLOEXEC) as u64;
+    how.resolve = libc::RESOLVE_NO_SYMLINKS;
+    let fd = unsafe {
+        libc::syscall(libc::SYS_openat2, libc::AT_FDCWD, path.as_ptr(), &how as *const libc::open_how, std::mem::size_of::<libc::open_how>())
+    };
+    if fd < 0 {
+        let error = std::io::Error::last_os_error();
+        return match error.raw_os_error() {
+            Some(libc::ENOSYS) => None,
+            _ => Some(Err(error)),
+        };
+    }
+    Some(Ok(unsafe { std::os::fd::OwnedFd::from_raw_fd(fd as libc::c_int) }))
+}
+
+/// fail unless path is absolute and free of . and .. components
+fn check_canonical(path: &Path) -> std::io::Result<()> {
+    use std::path::Component;
+    if !path.has_root() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "path is not absolute"));
+    }
+    match path.components().all(|component| matches!(component, Component::RootDir | Component::Normal(_))) {
+        true => Ok(()),
+        false => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "path is not canonical")),
+    }
+}
+
+/// open the regular file at absolute path for reading, as by open_nofollow
+/// never blocks on a FIFO or device swapped in after scanning; fails if path is not a regular file
+pub fn open_regular(path: &Path) -> std::io::Result<std::fs::File> {
+    let f = std::fs::File::from(open_nofollow(path, libc::O_RDONLY | libc::O_NONBLOCK)?);
+    match f.metadata()?.is_file() {
+        true => Ok(f),
+        false => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a regular file")),
+    }
+}
+
+/// path made absolute by canonicalizing its parent directory, keeping its last component as is
+/// unlike canonicalize, a symlink as the last component is not resolved
+pub fn absolute_nofollow(path: &Path) -> std::io::Result<std::path::PathBuf> {
+    match path.file_name() {
+        Some(name) => {
+            let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            Ok(parent.canonicalize()?.join(name))
+        },
+        None => path.canonicalize(), // root, or ending in ..
+    }
+}
+
+/// metadata of absolute path without following symlinks in any of its components
+pub fn metadata_nofollow(path: &Path) -> std::io::Result<Metadata> {
+    use std::os::fd::AsFd;
+    stat_fd(open_nofollow(path, libc::O_PATH)?.as_fd())
+}
+
+/// names of the entries of dir other than . and ..
+pub fn read_dir_names(dir: std::os::fd::BorrowedFd) -> std::io::Result<Vec<std::ffi::OsString>> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::ffi::OsStrExt;
+    let fd = unsafe { libc::fcntl(dir.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let stream = unsafe { libc::fdopendir(fd) }; // owns fd from here on
+    if stream.is_null() {
+        let error = std::io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(error);
+    }
+    unsafe { libc::rewinddir(stream) }; // the duplicate shares its offset with dir
+    let mut names = Vec::new();
+    let result = loop {
+        unsafe { *libc::__errno_location() = 0 };
+        let entry = unsafe { libc::readdir(stream) };
+        if entry.is_null() {
+            let error = std::io::Error::last_os_error();
+            break match error.raw_os_error() {
+                Some(0) => Ok(names),
+                _ => Err(error),
+            };
+        }
+        let name = unsafe { std::ffi::CStr::from_ptr((*entry).d_name.as_ptr()) }.to_bytes();
+        if name != b"." && name != b".." {
+            names.push(std::ffi::OsStr::from_bytes(name).to_os_string());
+        }
+    };
+    unsafe { libc::closedir(stream) };
+    result
+}
+
+/// hardlink old_name in old_dir to new_name in new_dir (linkat), not following symlinks
+pub fn link_at(
+    old_dir: std::os::fd::BorrowedFd,
+    old_name: &std::ffi::OsStr,
+    new_dir: std::os::fd::BorrowedFd,
+    new_name: &std::ffi::OsStr,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let (old_name, new_name) = (c_name(old_name)?, c_name(new_name)?);
+    check_errno(unsafe { libc::linkat(old_dir.as_raw_fd(), old_name.as_ptr(), new_dir.as_raw_fd(), new_name.as_ptr(), 0) })
+}
+
+/// rename old_name in old_dir to new_name in new_dir (renameat), replacing it if it exists
+pub fn rename_at(
+    old_dir: std::os::fd::BorrowedFd,
+    old_name: &std::ffi::OsStr,
+    new_dir: std::os::fd::BorrowedFd,
+    new_name: &std::ffi::OsStr,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let (old_name, new_name) = (c_name(old_name)?, c_name(new_name)?);
+    check_errno(unsafe { libc::renameat(old_dir.as_raw_fd(), old_name.as_ptr(), new_dir.as_raw_fd(), new_name.as_ptr()) })
+}
+
+/// remove the non-directory name in dir (unlinkat)
+pub fn unlink_at(dir: std::os::fd::BorrowedFd, name: &std::ffi::OsStr) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let name = c_name(name)?;
+    check_errno(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) })
+}
+
+fn c_name(name: &std::ffi::OsStr) -> std::io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(std::ffi::CString::new(name.as_bytes())?)
+}
+
+fn check_errno(ret: libc::c_int) -> std::io::Result<()> {
+    match ret {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    #[test]
+    pub fn test_open_nofollow_refuses_symlinks() {
+        let dir = TempDir::new("nofollow");
+        std::fs::create_dir(dir.join("real")).unwrap();
+        std::fs::write(dir.join("real/file"), b"contents").unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real/file"), dir.join("real/file-link")).unwrap();
+        assert!(open_nofollow(&dir.join("real/file"), libc::O_RDONLY).is_ok());
+        assert!(open_nofollow(&dir.join("link/file"), libc::O_RDONLY).is_err());
+        assert!(open_nofollow(&dir.join("real/file-link"), libc::O_RDONLY).is_err());
+        assert!(open_dir(&dir.join("link"), libc::O_RDONLY).is_err());
+        assert!(open_dir(&dir.join("real/../real"), libc::O_RDONLY).is_err());
+        assert!(metadata_nofollow(&dir.join("real/file-link")).unwrap().is_symlink());
+        let dir_fd = open_dir(&dir.join("real"), libc::O_RDONLY).unwrap();
+        use std::os::fd::AsFd;
+        assert!(stat_at(dir_fd.as_fd(), std::ffi::OsStr::new("file-link")).unwrap().is_symlink());
+        assert_eq!(stat_at(dir_fd.as_fd(), std::ffi::OsStr::new("file")).unwrap().st_size(), 8);
+    }
+}
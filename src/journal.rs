@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::os::linux::fs::MetadataExt as MetadataExtLinux;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::error::{Error, ErrorTally, Operation};
+use crate::event::{Event, OnEvent};
+use crate::fs::{metadata_nofollow, open_at, Metadata, open_parent, rename_at, unlink_at};
+use crate::link::{hardlink_path, temporary_name};
+
+
+
+/// write-ahead record of hardlink operations, read back by `undo`
+/// deduplicate syncs the records of each inode to disk before linking any of its paths,
+/// so a crash never leaves a link without its record; records of links not performed are skipped by undo
+/// one operation per line, tab separated:
+///   link KEEP REPLACE DEV INODE MODE UID GID ATIME ATIME_NSEC MTIME MTIME_NSEC
+/// where DEV through MTIME_NSEC describe the inode REPLACE had before the operation
+pub struct Journal {
+    path: PathBuf,
+    file: std::sync::Mutex<std::fs::File>,
+}
+
+pub struct JournalRecord {
+    pub keep: PathBuf,
+    pub replace: PathBuf,
+    pub dev: u64,
+    pub ino: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: i64,
+    pub atime_nsec: i64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+}
+
+impl Journal {
+    const HEADER: &str = "lndups-journal 1";
+
+    /// open for appending, creating it if needed
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let mut file = std::fs::OpenOptions::new().append(true).create(true).open(path)
+            .map_err(|e| Error::io(Operation::Open, path, e))?;
+        let empty = file.metadata().map_err(|e| Error::io(Operation::Stat, path, e))?.len() == 0;
+        if empty {
+            writeln!(file, "{}", Self::HEADER).map_err(|e| Error::io(Operation::Write, path, e))?;
+        }
+        Ok(Journal { path: path.to_path_buf(), file: std::sync::Mutex::new(file) })
+    }
+
+    /// record that `replace`, currently described by md, is about to be replaced by a hardlink to `keep`
+    pub fn append(&self, keep: &Path, replace: &Path, md: &Metadata) -> Result<(), Error> {
+        let line = format!("link\t{}\t{}\t{}\t{}\t{:o}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            escape_field(keep.as_os_str()),
+            escape_field(replace.as_os_str()),
+            md.st_dev(), md.st_ino(), md.st_mode(), md.st_uid(), md.st_gid(),
+            md.st_atime(), md.st_atime_nsec(), md.st_mtime(), md.st_mtime_nsec(),
+        );
+        self.file.lock().unwrap().write_all(line.as_bytes())
+            .map_err(|e| Error::io(Operation::Write, &self.path, e))
+    }
+
+    /// wait until every record appended so far is on disk
+    pub fn sync(&self) -> Result<(), Error> {
+        self.file.lock().unwrap().sync_data()
+            .map_err(|e| Error::io(Operation::Write, &self.path, e))
+    }
+
+    /// all records in order of operation
+    /// reports malformed lines to on_event
+    pub fn read(path: &Path, on_event: OnEvent) -> Result<Vec<JournalRecord>, Error> {
+        let reader = std::io::BufReader::new(std::fs::File::open(path).map_err(|e| Error::io(Operation::Open, path, e))?);
+        let mut records = Vec::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| Error::io(Operation::Read, path, e))?;
+            if i == 0 {
+                if line != Self::HEADER {
+                    return Err(Error::Malformed { path: path.to_path_buf(), reason: "Not a journal" });
+                }
+                continue;
+            }
+            match Self::parse_line(&line) {
+                Some(record) => records.push(record),
+                None => on_event(Event::MalformedJournalLine { journal: path, line: i+1 }),
+            }
+        }
+        Ok(records)
+    }
+
+    fn parse_line(line: &str) -> Option<JournalRecord> {
+        let mut fields = line.split('\t');
+        let mut next = || fields.next();
+        if next()? != "link" {
+            return None;
+        }
+        let record = JournalRecord {
+            keep: PathBuf::from(unescape_field(next()?)?),
+            replace: PathBuf::from(unescape_field(next()?)?),
+            dev: next()?.parse().ok()?,
+            ino: next()?.parse().ok()?,
+            mode: u32::from_str_radix(next()?, 8).ok()?,
+            uid: next()?.parse().ok()?,
+            gid: next()?.parse().ok()?,
+            atime: next()?.parse().ok()?,
+            atime_nsec: next()?.parse().ok()?,
+            mtime: next()?.parse().ok()?,
+            mtime_nsec: next()?.parse().ok()?,
+        };
+        if next().is_some() {
+            return None;
+        }
+        Some(record)
+    }
+}
+
+
+/// escape backslashes, tabs, newlines and carriage returns, and invalid unicode as \xHH
+pub fn escape_field(s: &std::ffi::OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let mut result = String::with_capacity(s.len());
+    for chunk in s.as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => result.push_str("\\\\"),
+                '\t' => result.push_str("\\t"),
+                '\n' => result.push_str("\\n"),
+                '\r' => result.push_str("\\r"),
+                c => result.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            result.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    result
+}
+
+/// reverse of escape_field
+pub fn unescape_field(s: &str) -> Option<std::ffi::OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    let mut result = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            result.push(b);
+            continue;
+        }
+        match bytes.next()? {
+            b'\\' => result.push(b'\\'),
+            b't' => result.push(b'\t'),
+            b'n' => result.push(b'\n'),
+            b'r' => result.push(b'\r'),
+            b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                result.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            },
+            _ => return None,
+        }
+    }
+    Some(std::ffi::OsString::from_vec(result))
+}
+
+
+/// outcome of undoing a journal
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UndoSummary {
+    /// hardlinks split
+    pub split: usize,
+    pub records: usize,
+    pub errors: ErrorTally,
+}
+
+/// split hardlinks recorded in a journal, most recent first
+/// paths replaced from the same original inode are linked to each other again
+/// reports each record to on_event; fails only if the journal can't be read
+pub fn undo(journal: &Path, cfg: &Config, on_event: OnEvent) -> Result<UndoSummary, Error> {
+    let records = Journal::read(journal, on_event)?;
+    let mut restored: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut number_split = 0;
+    let mut errors = ErrorTally::default();
+    for record in records.iter().rev() {
+        if cfg.cancelled() {
+            break;
+        }
+        let (keep, replace) = (record.keep.as_path(), record.replace.as_path());
+        let linked = match (metadata_nofollow(&record.keep), metadata_nofollow(&record.replace)) {
+            (Ok(keep_md), Ok(replace_md)) =>
+                (keep_md.st_dev(), keep_md.st_ino()) == (replace_md.st_dev(), replace_md.st_ino()),
+            _ => false,
+        };
+        if !linked {
+            on_event(Event::NotLinked { keep, replace });
+            continue;
+        }
+        if !cfg.dry_run {
+            let result = match restored.get(&(record.dev, record.ino)) {
+                Some(sibling) => hardlink_path(sibling, &record.replace),
+                None => split_hardlink(record),
+            };
+            if let Err(error) = result {
+                on_event(Event::UnlinkFailed { keep, replace, error: &error });
+                errors.record(&error);
+                continue;
+            }
+        }
+        restored.entry((record.dev, record.ino)).or_insert_with(|| record.replace.clone());
+        number_split += 1;
+        on_event(Event::Unlinked { keep, replace });
+    }
+    Ok(UndoSummary { split: number_split, records: records.len(), errors })
+}
+
+/// atomically replace `record.replace` with an independent copy of itself,
+/// restoring the owner, permissions and timestamps in record
+fn split_hardlink(record: &JournalRecord) -> Result<(), Error> {
+    use std::os::fd::AsFd;
+    use std::os::unix::fs::PermissionsExt;
+    let replace = &record.replace;
+    let (dir, name) = open_parent(replace)
+        .map_err(|e| Error::io(Operation::Open, replace.parent().unwrap_or(replace), e))?;
+    let mut src = std::fs::File::from(open_at(dir.as_fd(), name, libc::O_RDONLY, 0)
+        .map_err(|e| Error::io(Operation::Open, replace, e))?);
+    let (tmp, mut dst) = loop {
+        let tmp = temporary_name();
+        match open_at(dir.as_fd(), &tmp, libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL, 0o600) {
+            Ok(dst) => break (tmp, std::fs::File::from(dst)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(Error::io(Operation::Create, replace.with_file_name(&tmp), e)),
+        }
+    };
+    let copy = replace.with_file_name(&tmp);
+    let result = (|| {
+        std::io::copy(&mut src, &mut dst).map_err(|e| Error::io(Operation::Copy, replace, e))?;
+        let md = dst.metadata().map_err(|e| Error::io(Operation::Stat, &copy, e))?;
+        if (md.st_uid(), md.st_gid()) != (record.uid, record.gid) {
+            std::os::unix::fs::fchown(&dst, Some(record.uid), Some(record.gid))
+                .map_err(|e| Error::io(Operation::SetOwner, &copy, e))?;
+        }
+        dst.set_permissions(std::fs::Permissions::from_mode(record.mode & 0o7777))
+            .map_err(|e| Error::io(Operation::SetPermissions, &copy, e))?;
+        dst.set_times(std::fs::FileTimes::new()
+            .set_accessed(system_time(record.atime, record.atime_nsec))
+            .set_modified(system_time(record.mtime, record.mtime_nsec)))
+            .map_err(|e| Error::io(Operation::SetTimes, &copy, e))?;
+        rename_at(dir.as_fd(), &tmp, dir.as_fd(), name).map_err(|e| Error::io(Operation::Rename, &copy, e))
+    })();
+    if result.is_err() {
+        let _ = unlink_at(dir.as_fd(), &tmp);
+    }
+    result
+}
+
+/// time from seconds and nanoseconds since the epoch as in struct stat
+pub fn system_time(sec: i64, nsec: i64) -> std::time::SystemTime {
+    let t = std::time::UNIX_EPOCH + std::time::Duration::from_nanos(nsec as u64);
+    match sec >= 0 {
+        true => t + std::time::Duration::from_secs(sec as u64),
+        false => t - std::time::Duration::from_secs(sec.unsigned_abs()),
+    }
+}
+
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    #[test]
+    pub fn test_escape_field_roundtrip() {
+        use std::os::unix::ffi::OsStrExt;
+        let s = std::ffi::OsStr::from_bytes(b"a\tb\\c\nd\xffe");
+        let escaped = escape_field(s);
+        assert!(!escaped.contains(['\t', '\n']));
+        assert_eq!(unescape_field(&escaped).unwrap(), s);
+    }
+    #[test]
+    pub fn test_undo_roundtrip() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("undo");
+        for name in ["keep", "b", "c"] {
+            std::fs::write(dir.join(name), b"contents").unwrap();
+        }
+        std::fs::set_permissions(dir.join("b"), std::fs::Permissions::from_mode(0o640)).unwrap();
+        std::fs::hard_link(dir.join("b"), dir.join("b2")).unwrap();
+        let journal = Journal::open(&dir.join("journal")).unwrap();
+        for name in ["b", "b2", "c"] {
+            let md = metadata_nofollow(&dir.join(name)).unwrap();
+            journal.append(&dir.join("keep"), &dir.join(name), &md).unwrap();
+        }
+        journal.sync().unwrap();
+        for name in ["b", "b2"] { // c was journaled but never linked
+            hardlink_path(&dir.join("keep"), &dir.join(name)).unwrap();
+        }
+        assert_eq!(metadata_nofollow(&dir.join("keep")).unwrap().st_nlink(), 3);
+
+        let summary = undo(&dir.join("journal"), &Config::builder().build(), &|_| {}).unwrap();
+        assert_eq!((summary.split, summary.records), (2, 3));
+        assert!(summary.errors.is_empty());
+        let md = |name| metadata_nofollow(&dir.join(name)).unwrap();
+        assert_eq!(md("keep").st_nlink(), 1);
+        assert_eq!(md("b").st_ino(), md("b2").st_ino()); // linked to each other again
+        assert_ne!(md("b").st_ino(), md("keep").st_ino());
+        assert_eq!(md("b").st_mode() & 0o7777, 0o640);
+        assert_eq!(std::fs::read(dir.join("b")).unwrap(), b"contents");
+    }
+}
@@ -0,0 +1,42 @@
+//! Hardlink duplicate files
+//!
+//! [scan] walks targets into a [Registry] of files by size,
+//! and [deduplicate] links the duplicates within it as set up by a [Config]
+//! neither prints anything; what happens is reported to a callback as [Event]s
+//!
+//! ```no_run
+//! let config = lndups::Config::builder().dry_run(true).build();
+//! let on_event = |event: lndups::Event| if let lndups::Event::Error(error) = event {
+//!     eprintln!("{}", error);
+//! };
+//! let target = lndups::PathWithMetadata::new("/srv/data".into()).unwrap();
+//! let (registry, scan_errors) = lndups::scan(vec![target], &config, &on_event);
+//! let summary = lndups::deduplicate(registry, &config, &on_event);
+//! println!("{} bytes would be freed", summary.bytes_freed);
+//! println!("{} errors", scan_errors.total() + summary.errors.total());
+//! ```
+
+pub mod cache;
+pub mod config;
+pub mod dedup;
+pub mod error;
+pub mod event;
+pub(crate) mod fs;
+pub mod journal;
+pub mod link;
+pub mod progress;
+pub mod scan;
+pub mod signal;
+#[cfg(test)]
+mod testing;
+
+pub use cache::HashCache;
+pub use config::{Config, ConfigBuilder, KeepPolicy, LinkMode};
+pub use dedup::{deduplicate, Summary};
+pub use error::{Error, ErrorTally, Operation};
+pub use event::{Event, OnEvent};
+pub use fs::Metadata;
+pub use journal::{undo, Journal, UndoSummary};
+pub use progress::{Phase, Progress, ProgressSnapshot};
+pub use scan::{scan, PathWithMetadata, Registry};
+pub use signal::{install_signal_handlers, interrupted};
@@ -0,0 +1,219 @@
+use std::path::Path;
+
+use crate::config::LinkMode;
+use crate::dedup::cmp;
+use crate::error::{Error, Operation};
+use crate::fs::{link_at, metadata_nofollow, open_nofollow, open_parent, rename_at, unlink_at, Metadata};
+use crate::scan::PathWithMetadata;
+
+
+
+/// replace `replace` with a hardlink to `keep`
+/// atomic: `replace` always exists with either its old or new contents
+pub fn hardlink(
+    keep: &PathWithMetadata,
+    replace: &PathWithMetadata
+) -> Result<(), Error> {
+    hardlink_path(&keep.path, &replace.path)?;
+    replace.reset_md()?;
+    keep.reset_md()?; // link count and ctime changed
+    Ok(())
+}
+
+/// replace `replace` with a hardlink to `keep`
+/// atomic: `replace` always exists with either its old or new contents
+/// works relative to the parent directories, so symlinks in any component are never followed
+pub fn hardlink_path(keep: &Path, replace: &Path) -> Result<(), Error> {
+    use std::os::fd::AsFd;
+    let parent = |path: &Path| path.parent().unwrap_or(path).to_path_buf();
+    let (keep_dir, keep_name) = open_parent(keep).map_err(|e| Error::io(Operation::Open, parent(keep), e))?;
+    let (dir, name) = open_parent(replace).map_err(|e| Error::io(Operation::Open, parent(replace), e))?;
+    let tmp = loop {
+        let tmp = temporary_name();
+        match link_at(keep_dir.as_fd(), keep_name, dir.as_fd(), &tmp) {
+            Ok(()) => break tmp,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) if e.raw_os_error() == Some(libc::EMLINK) => return Err(Error::TooManyLinks { path: keep.to_path_buf() }),
+            Err(e) => return Err(Error::io(Operation::Link, keep, e)),
+        }
+    };
+    if let Err(e) = rename_at(dir.as_fd(), &tmp, dir.as_fd(), name) {
+        let _ = unlink_at(dir.as_fd(), &tmp);
+        return Err(Error::io(Operation::Rename, replace, e));
+    }
+    Ok(())
+}
+
+/// a temporary name in the directory of a file being replaced, unique to this process
+/// short and of bounded length, so it never exceeds NAME_MAX whatever the name replaced
+/// may still exist; callers must create it exclusively
+pub(crate) fn temporary_name() -> std::ffi::OsString {
+    static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    std::ffi::OsString::from(format!(".lndups-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+    ))
+}
+
+/// make `replace` share the extents of `keep` (FICLONE)
+/// `replace` keeps its own inode, permissions and timestamps
+pub fn reflink(
+    keep: &PathWithMetadata,
+    replace: &PathWithMetadata
+) -> Result<(), Error> {
+    use std::os::fd::AsRawFd;
+    let src = open_nofollow(&keep.path, libc::O_RDONLY).map_err(|e| Error::io(Operation::Open, &keep.path, e))?;
+    let dst = std::fs::File::from(open_nofollow(&replace.path, libc::O_WRONLY)
+        .map_err(|e| Error::io(Operation::Open, &replace.path, e))?);
+    let times = {
+        let md = replace.md();
+        std::fs::FileTimes::new().set_accessed(md.accessed()).set_modified(md.modified())
+    };
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } != 0 {
+        return Err(Error::io(Operation::Reflink, &replace.path, std::io::Error::last_os_error()));
+    }
+    dst.set_times(times).map_err(|e| Error::io(Operation::SetTimes, &replace.path, e))?;
+    replace.reset_md()?;
+    Ok(())
+}
+
+/// confirm that `replace` now shares storage with `keep`
+/// hardlink: `replace` is the inode of `keep`, whose link count rose from keep_nlink
+/// otherwise: `replace` is still its original inode, with the same contents as `keep`
+pub fn verify_link(
+    keep: &PathWithMetadata,
+    replace: &PathWithMetadata,
+    keep_nlink: u64,
+    replaced_md: &Metadata,
+    mode: LinkMode,
+) -> Result<(), Error> {
+    let keep_md = metadata_nofollow(&keep.path).map_err(|e| Error::io(Operation::Stat, &keep.path, e))?;
+    let replace_md = metadata_nofollow(&replace.path).map_err(|e| Error::io(Operation::Stat, &replace.path, e))?;
+    let unverified = |reason| Err(Error::Unverified { path: replace.path.clone(), reason });
+    let keep_id = (keep_md.st_dev(), keep_md.st_ino());
+    let replace_id = (replace_md.st_dev(), replace_md.st_ino());
+    match mode {
+        LinkMode::Hardlink => {
+            if replace_id != keep_id {
+                return unverified("is not the kept inode");
+            }
+            if keep_md.st_nlink() <= keep_nlink {
+                return unverified("did not raise the link count of the kept inode");
+            }
+        },
+        LinkMode::Reflink | LinkMode::DedupeRange => {
+            if replace_id != (replaced_md.st_dev(), replaced_md.st_ino()) {
+                return unverified("is no longer its original inode");
+            }
+            if !cmp(&keep.path, &replace.path)? {
+                return unverified("differs from the kept file");
+            }
+        },
+    }
+    Ok(())
+}
+
+/// struct file_dedupe_range_info from linux/fs.h
+#[repr(C)]
+struct FileDedupeRangeInfo {
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+/// struct file_dedupe_range from linux/fs.h, with a single destination
+#[repr(C)]
+struct FileDedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    info: [FileDedupeRangeInfo; 1],
+}
+
+/// _IOWR(0x94, 54, struct file_dedupe_range)
+const FIDEDUPERANGE: libc::Ioctl = 0xC0189436u32 as libc::Ioctl;
+const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+/// make `replace` share the extents of `keep` (FIDEDUPERANGE)
+/// the kernel only shares extents whose contents are identical
+/// `replace` keeps its own inode, permissions and timestamps
+/// returns number of bytes deduped, which is always the full size on success
+pub fn dedupe_range(
+    keep: &PathWithMetadata,
+    replace: &PathWithMetadata
+) -> Result<u64, Error> {
+    use std::os::fd::AsRawFd;
+    let src = open_nofollow(&keep.path, libc::O_RDONLY).map_err(|e| Error::io(Operation::Open, &keep.path, e))?;
+    // a read-only destination suffices for the owner of the file
+    let dst = open_nofollow(&replace.path, libc::O_WRONLY)
+        .or_else(|_| open_nofollow(&replace.path, libc::O_RDONLY))
+        .map_err(|e| Error::io(Operation::Open, &replace.path, e))?;
+    let size = keep.md().st_size();
+    let mut offset = 0;
+    while offset < size {
+        let mut range = FileDedupeRange {
+            src_offset: offset,
+            src_length: size - offset,
+            dest_count: 1,
+            reserved1: 0,
+            reserved2: 0,
+            info: [FileDedupeRangeInfo {
+                dest_fd: dst.as_raw_fd() as i64,
+                dest_offset: offset,
+                bytes_deduped: 0,
+                status: 0,
+                reserved: 0,
+            }],
+        };
+        if unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE, &mut range) } != 0 {
+            return Err(Error::io(Operation::Dedupe, &replace.path, std::io::Error::last_os_error()));
+        }
+        let info = &range.info[0];
+        if info.status < 0 {
+            return Err(Error::io(Operation::Dedupe, &replace.path, std::io::Error::from_raw_os_error(-info.status)));
+        }
+        let partial = |differs| Err(Error::PartiallyDeduped { path: replace.path.clone(), deduped: offset, size, differs });
+        if info.status == FILE_DEDUPE_RANGE_DIFFERS {
+            return partial(true);
+        }
+        if info.bytes_deduped == 0 { // no progress; avoid looping forever
+            return partial(false);
+        }
+        offset += info.bytes_deduped;
+    }
+    replace.reset_md()?;
+    Ok(offset)
+}
+
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    #[test]
+    pub fn test_hardlink_path_refuses_symlinked_directory() {
+        let dir = TempDir::new("link");
+        std::fs::create_dir_all(dir.join("outside")).unwrap();
+        std::fs::write(dir.join("keep"), b"contents").unwrap();
+        std::fs::write(dir.join("outside/replace"), b"contents").unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), dir.join("swapped")).unwrap();
+        assert!(hardlink_path(&dir.join("keep"), &dir.join("swapped/replace")).is_err());
+        assert_eq!(metadata_nofollow(&dir.join("outside/replace")).unwrap().st_nlink(), 1);
+        hardlink_path(&dir.join("keep"), &dir.join("outside/replace")).unwrap();
+        assert_eq!(metadata_nofollow(&dir.join("keep")).unwrap().st_nlink(), 2);
+    }
+    #[test]
+    pub fn test_hardlink_path_longest_name() {
+        let dir = TempDir::new("link-long");
+        let name = "r".repeat(255); // NAME_MAX
+        std::fs::write(dir.join("keep"), b"contents").unwrap();
+        std::fs::write(dir.join(&name), b"contents").unwrap();
+        hardlink_path(&dir.join("keep"), &dir.join(&name)).unwrap();
+        assert_eq!(metadata_nofollow(&dir.join(&name)).unwrap().st_nlink(), 2);
+    }
+}
@@ -1,17 +1,25 @@
 
-use std::collections::HashMap;
-use std::io::{Read, Write, BufRead};
-use std::os::linux::fs::MetadataExt as MetadataExtLinux;
+use std::io::{Write, BufRead};
 use std::path::{Path, PathBuf};
 
 use clap::Parser;
-use smallvec::*;
+use lndups::{Config, Error, ErrorTally, Event, HashCache, Journal, KeepPolicy, LinkMode, Operation, PathWithMetadata, Progress};
+use lndups::{deduplicate, install_signal_handlers, interrupted, scan, undo};
+use lndups::scan::{check_all_same_device, partition_by_device};
+
+mod output;
+use output::{error_tally_json, JsonStr, Output, ProgressLine};
 
 
 
 #[macro_export] macro_rules! s_arg_target_file_name { () => { "target-file" } }
 #[macro_export] macro_rules! s_default_target_separator { () => { ";" } }
-#[macro_export] macro_rules! s_value_absolute_min_size { () => { "1" } }
+#[macro_export] macro_rules! s_value_absolute_min_size { () => { "1" } } // lndups::config::ABSOLUTE_MIN_SIZE
+
+/// exit status when some files could not be scanned, compared or linked
+const EXIT_PARTIAL_FAILURE: u8 = 1;
+/// exit status when nothing could be done; clap uses the same for invalid arguments
+const EXIT_FATAL: u8 = 2;
 
 
 
@@ -22,38 +30,138 @@ use smallvec::*;
         "\nThis tool should only be used when you are sure that duplicate files should remain duplicate in perpetuity",
     ),
     // usage=concat!(env!("CARGO_PKG_NAME"), " [OPTION]... TARGET... ['", s_default_target_separator!(), "' TARGET...]")
+    after_help=concat!(
+        "Exit status:\n",
+        "  0      Success\n",
+        "  1      Some files could not be scanned, compared or linked; the summary tallies the errors\n",
+        "  2      Fatal error, such as invalid arguments or an unreadable journal\n",
+        "  128+N  Interrupted by signal N",
+    ),
+    disable_help_subcommand=true,
 )]
 pub struct Arguments {
-    #[arg(short, long, action=clap::ArgAction::Count, help="Increase verbosity")]
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[arg(short, long, global=true, action=clap::ArgAction::Count, help="Increase verbosity")]
     pub verbose: u8,
 
-    #[arg(short, long, action=clap::ArgAction::Count, help="Decrease verbosity")]
+    #[arg(short, long, global=true, action=clap::ArgAction::Count, help="Decrease verbosity")]
     pub quiet: u8,
 
-    #[arg(short, long="raw-output", help=concat!(
+    #[arg(short, long="raw-output", global=true, help=concat!(
         "Show only hardlink operations and errors, in an easily parseable format\n",
         "  Outputs two columns separated by a tab\n",
         "  Bypasses verbosity",
     ))]
     pub raw_output_only: bool,
 
-    #[arg(short, long, help=concat!(
+    #[arg(short, long="json", conflicts_with="raw_output_only", global=true, help=concat!(
+        "Output a stream of JSON objects, one per line, instead of text\n",
+        "  Each object has an \"event\" field: scan_started, group_found, link_performed, link_failed,\n",
+        "    link_limit_reached, error or summary\n",
+        "  Undo outputs unlinked, unlink_failed, not_linked, malformed_journal_line and undo_summary\n",
+        "  error, link_failed and unlink_failed carry the failed \"operation\" and its \"errno\",\n",
+        "    or null if not a system error\n",
+        "  Summaries tally errors by category in \"errors\"\n",
+        "  Bypasses verbosity",
+    ))]
+    pub json_output: bool,
+
+    #[arg(short='0', long="null", global=true, help=concat!(
+        "Use null bytes instead of newlines to delimit paths\n",
+        "  Applies to --", s_arg_target_file_name!(), " and --raw-output\n",
+        "  Raw output separates columns and terminates lines with a null byte",
+    ))]
+    pub null_delimited: bool,
+
+    #[arg(short, long, global=true, help=concat!(
         "Disable brace notation for output\n",
         "  Ex: /home/user/{dir,backup}/file",
     ))]
     pub no_brace_output: bool,
 
-    #[arg(short, long, help=concat!(
+    #[arg(long, help=concat!(
+        "Don't show progress on stderr\n",
+        "  Progress is only shown when stderr is a terminal and verbosity is not decreased",
+    ))]
+    pub no_progress: bool,
+
+    #[arg(short, long, global=true, help=concat!(
         "Perform no operations on the filesystem",
     ))]
     pub dry_run: bool,
 
+    #[arg(long, help=concat!(
+        "List each set of identical files instead of linking\n",
+        "  Performs no operations on the filesystem\n",
+        "  Paths of the inode that would be kept are listed first\n",
+        "  With --raw-output, outputs one path per line with an empty line after each set",
+    ))]
+    pub report: bool,
+
+    #[arg(long, help=concat!(
+        "Share extents (FICLONE) instead of hardlinking\n",
+        "  Files keep their own inode, permissions and timestamps\n",
+        "  Requires a filesystem supporting reflinks, such as btrfs or XFS",
+    ))]
+    pub reflink: bool,
+
+    #[arg(long, conflicts_with="reflink", help=concat!(
+        "Share extents (FIDEDUPERANGE) instead of hardlinking\n",
+        "  Like --reflink, but the kernel verifies contents are identical before sharing\n",
+        "  Reports bytes deduplicated per file",
+    ))]
+    pub dedupe_range: bool,
+
+    #[arg(long, value_name="FILE",
+        conflicts_with_all=["reflink", "dedupe_range"], help=concat!(
+        "Append every hardlink operation to FILE before performing it\n",
+        "  Records the original owner, permissions and timestamps of replaced files\n",
+        "  Records are synced to disk before the files they describe are linked\n",
+        "  Operations can be reverted with `", env!("CARGO_PKG_NAME"), " undo FILE`",
+    ))]
+    pub journal: Option<PathBuf>,
+
     #[arg(short='i', long, help=concat!(
         "Prompt once before operating\n",
         "  Doesn't occurs if no targets are provided",
     ))]
     pub prompt: bool,
 
+    #[arg(long, help=concat!(
+        "Confirm every operation took effect\n",
+        "  Hardlinks must refer to the kept inode, whose link count must have risen\n",
+        "  Reflinked and deduped files must keep their inode and match the kept file\n",
+        "  Mismatches are reported as failures",
+    ))]
+    pub verify: bool,
+
+    #[arg(long, help=concat!(
+        "Only link files with the same owner and group",
+    ))]
+    pub require_same_owner: bool,
+
+    #[arg(long, help=concat!(
+        "Only link files with the same permissions",
+    ))]
+    pub require_same_mode: bool,
+
+    #[arg(long, help=concat!(
+        "Only link files with the same extended attributes\n",
+        "  Includes POSIX ACLs",
+    ))]
+    pub require_same_xattrs: bool,
+
+    #[arg(short, long, value_name="POLICY", value_enum,
+        default_value_t=KeepArg::MostLinks, help=concat!(
+        "Which of a set of identical files keeps its inode\n",
+        "  Its owner, permissions and timestamps are retained\n",
+        "  most-links: most paths among targets, then most hardlinks overall\n",
+        "  path-priority: found under the earliest given target of the set",
+    ))]
+    pub keep: KeepArg,
+
     #[arg(short, long, value_name="SIZE",
         default_value=s_value_absolute_min_size!(), help=concat!(
         "Minimum file size to be considered for hardlinking\n",
@@ -61,6 +169,22 @@ pub struct Arguments {
     ))]
     pub min_size: u64,
 
+    #[arg(long, value_name="GLOB", value_parser=parse_glob, help=concat!(
+        "Skip files and directories matching GLOB\n",
+        "  Matched against both the full path and the file name\n",
+        "  Excluded directories are not descended into\n",
+        "  May be given multiple times",
+    ))]
+    pub exclude: Vec<glob::Pattern>,
+
+    #[arg(long, value_name="GLOB", value_parser=parse_glob, help=concat!(
+        "Only consider files matching GLOB\n",
+        "  Matched against both the full path and the file name\n",
+        "  Directories are always descended into\n",
+        "  May be given multiple times",
+    ))]
+    pub include: Vec<glob::Pattern>,
+
 
     #[arg(short, long, value_name="NUMBER",
         default_value="2", help=concat!(
@@ -68,11 +192,30 @@ pub struct Arguments {
     ))]
     pub threads: usize,
 
+    #[arg(long, help=concat!(
+        "Partition each set of targets by device instead of requiring a single device\n",
+        "  Files found on other devices while recursing are only compared with files on the same device\n",
+        "  Each partition is processed independently",
+    ))]
+    pub auto_split_devices: bool,
+
+    #[arg(short='x', long, help=concat!(
+        "Don't descend into directories on other devices than their target\n",
+        "  Skipped mount points are listed with --verbose",
+    ))]
+    pub one_file_system: bool,
+
+    #[arg(long, value_name="DEPTH", help=concat!(
+        "Descend at most DEPTH directory levels below each target\n",
+        "  0 only considers targets which are files themselves",
+    ))]
+    pub max_depth: Option<usize>,
+
     #[arg(short, long, value_name="SEPARATOR",
         default_value=s_default_target_separator!(), help=concat!(
         "Separator between sets of targets",
     ))]
-    pub separator: String,
+    pub separator: std::ffi::OsString,
 
     #[arg(short, long=s_arg_target_file_name!(),
         value_name="FILE", help=concat!(
@@ -80,137 +223,349 @@ pub struct Arguments {
         "  Same rules as CLI argument targets apply\n",
         "  Mutually exclusive with CLI argument targets",
     ))]
-    pub file_containing_targets: Option<String>,
+    pub file_containing_targets: Option<PathBuf>,
+
+    #[arg(short, long, help=concat!(
+        "Cache content hashes between runs\n",
+        "  Entries are invalidated when a file's size, mtime or ctime changes\n",
+        "  Entries not used for 30 days are dropped\n",
+        "  Stored in $XDG_CACHE_HOME/", env!("CARGO_PKG_NAME"), "/hashes unless --cache-file is given",
+    ))]
+    pub cache: bool,
+
+    #[arg(long, value_name="FILE", help=concat!(
+        "Cache content hashes between runs in FILE\n",
+        "  Implies --cache",
+    ))]
+    pub cache_file: Option<PathBuf>,
 
     #[arg(value_name="TARGET", help=concat!(
         "Target files and directories (recursive)\n",
         "  Each SEPARATOR denotes a new set of targets\n",
         "    Each set of targets are separate from all other sets\n",
-        "    All targets in a set must be on the same device\n",
-        "  Symlinks are ignored\n",
+        "    All targets in a set must be on the same device, unless --auto-split-devices\n",
+        "  Symlinks given as targets are followed\n",
+        "  Symlinks found while recursing are ignored and never followed, even if swapped in after scanning\n",
         "  '-' is not treated as special\n",
         "  Mutually exclusive with --", s_arg_target_file_name!(),
     ))]
-    pub targets: Vec<String>,
+    pub targets: Vec<std::ffi::OsString>,
 }
 
-
-
-pub struct Config {
-    dry_run: bool,
-    min_size: u64,
-    verbosity: i16,
-    raw_output_only: bool,
-    no_brace_output: bool,
+#[derive(clap::Subcommand)]
+pub enum Command {
+    #[command(
+        about="Split hardlinks recorded in a journal back into independent files",
+        long_about=concat!(
+            "Split hardlinks recorded in a journal back into independent files\n",
+            "\nThe most recent operations are reverted first; ",
+            "paths that are no longer linked to the file they were linked to are skipped",
+        ),
+    )]
+    Undo {
+        #[arg(value_name="JOURNAL", help="Journal written with --journal")]
+        journal: PathBuf,
+    },
 }
 
 
-pub type Registry = HashMap<u64, Vec<PathWithMetadata>>;
+/// values of --keep; the library's KeepPolicy stays free of clap
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum KeepArg {
+    MostLinks,
+    OldestMtime,
+    NewestMtime,
+    PathPriority,
+    LowestInode,
+}
+impl From<KeepArg> for KeepPolicy {
+    fn from(keep: KeepArg) -> KeepPolicy {
+        match keep {
+            KeepArg::MostLinks => KeepPolicy::MostLinks,
+            KeepArg::OldestMtime => KeepPolicy::OldestMtime,
+            KeepArg::NewestMtime => KeepPolicy::NewestMtime,
+            KeepArg::PathPriority => KeepPolicy::PathPriority,
+            KeepArg::LowestInode => KeepPolicy::LowestInode,
+        }
+    }
+}
 
 
 
-pub fn main() -> Result<(), i32> {
-    let mut args = Arguments::parse();
+pub fn main() -> std::process::ExitCode {
+    let args = Arguments::parse();
     let verbosity = args.verbose as i16 - args.quiet as i16;
+    let result = try_main(args, verbosity);
+    if let Err(error) = &result && verbosity >= 0 {
+        eprintln!("{}", error);
+    }
+    if let Some(signal) = interrupted() {
+        return std::process::ExitCode::from((128 + signal) as u8);
+    }
+    match result {
+        Ok(errors) if errors.is_empty() => std::process::ExitCode::SUCCESS,
+        Ok(_) => std::process::ExitCode::from(EXIT_PARTIAL_FAILURE),
+        Err(_) => std::process::ExitCode::from(EXIT_FATAL),
+    }
+}
 
+/// everything main does; returns the tally of errors that did not stop it
+fn try_main(mut args: Arguments, verbosity: i16) -> Result<ErrorTally, Error> {
     rayon::ThreadPoolBuilder::new()
         .num_threads(std::cmp::max(args.threads, 1))
         .build_global()
         .unwrap();
 
-    let config = Config {
-        min_size: std::cmp::max(args.min_size, s_value_absolute_min_size!().parse::<u64>().unwrap()),
-        no_brace_output: args.no_brace_output,
-        dry_run: args.dry_run,
-        raw_output_only: args.raw_output_only,
-        verbosity
-    };
+    let builder = Config::builder()
+        .min_size(args.min_size)
+        .exclude(std::mem::take(&mut args.exclude))
+        .include(std::mem::take(&mut args.include))
+        .dry_run(args.dry_run)
+        .report(args.report)
+        .verify(args.verify)
+        .require_same_owner(args.require_same_owner)
+        .require_same_mode(args.require_same_mode)
+        .require_same_xattrs(args.require_same_xattrs)
+        .auto_split_devices(args.auto_split_devices)
+        .one_file_system(args.one_file_system)
+        .max_depth(args.max_depth)
+        .keep(args.keep.into())
+        .link_mode(match (args.reflink, args.dedupe_range) {
+            (true, _) => LinkMode::Reflink,
+            (_, true) => LinkMode::DedupeRange,
+            _ => LinkMode::Hardlink,
+        });
+    let mut output = Output::new(
+        verbosity,
+        args.raw_output_only,
+        args.json_output,
+        args.null_delimited,
+        args.no_brace_output,
+    );
+    let machine_output = output.machine_output();
+
+    if let Some(Command::Undo { journal }) = &args.command {
+        let config = builder.build();
+        install_signal_handlers();
+        let summary = undo(journal, &config, &|event| output.event(event, &config))?;
+        if output.json_output {
+            output.print(|out| writeln!(out,
+                "{{\"event\":\"undo_summary\",\"dry_run\":{},\"interrupted\":{},\"records\":{},\"split\":{},\"errors\":{}}}",
+                config.dry_run(),
+                interrupted().is_some(),
+                summary.records,
+                summary.split,
+                error_tally_json(&summary.errors),
+            ));
+        } else if verbosity >= 0 && !machine_output {
+            output.print(|out| writeln!(out, "Split {}/{} journaled hardlinks", summary.split, summary.records));
+        }
+        print_error_tally(&summary.errors, &output);
+        return Ok(summary.errors);
+    }
 
-    let run_targets: Vec<Vec<&String>> = obtain_run_targets(
+    let run_targets: Vec<Vec<&std::ffi::OsString>> = obtain_run_targets(
         args.file_containing_targets.as_ref(),
         &mut args.targets,
         &args.separator,
-        verbosity,
+        args.null_delimited,
     )?;
     if run_targets.is_empty() {
-        if verbosity >= 0 && !config.raw_output_only {
+        if verbosity >= 0 && !machine_output {
             use clap::CommandFactory;
             Arguments::command().print_help().unwrap();
         }
-        return Ok(());
+        return Ok(ErrorTally::default());
     }
 
     let run_paths: Vec<Vec<PathWithMetadata>> = obtain_run_paths(
         run_targets.iter().map(|v| v.iter()),
-        verbosity,
     )?;
 
-    for paths in &run_paths {
-        if let Err(s) = check_all_same_device(paths) {
-            eprintln!("{}", s);
-            return Err(1);
-        }
+    let run_paths = match args.auto_split_devices {
+        true => run_paths.into_iter().flat_map(|paths| {
+            let partitions = partition_by_device(paths);
+            if verbosity >= 1 && partitions.len() > 1 {
+                eprintln!("Split set of targets into {} partitions by device", partitions.len());
+            }
+            partitions
+        }).collect(),
+        false => {
+            for paths in &run_paths {
+                check_all_same_device(paths)?;
+            }
+            run_paths
+        },
+    };
+
+    if run_paths.is_empty() {
+        return Ok(ErrorTally::default());
     }
 
-    if run_paths.len() == 0 {
-        return Ok(());
+    if args.prompt
+        && !prompt_confirm(&run_targets).map_err(|e| Error::io(Operation::Read, "-", e))? {
+        return Ok(ErrorTally::default());
     }
 
-    if args.prompt {
-        if !prompt_confirm(&run_targets).map_err(|_| { eprintln!("IO Error during confirmation prompt"); 1 })? {
-            return Ok(());
+    // only once confirmed, so a signal at the prompt terminates
+    install_signal_handlers();
+
+    let hash_cache = match args.cache || args.cache_file.is_some() {
+        true => {
+            let path = args.cache_file.or_else(HashCache::default_path).ok_or_else(|| Error::Usage(
+                "Could not determine hash cache location; set XDG_CACHE_HOME or use --cache-file".to_string()
+            ))?;
+            Some(HashCache::load(path)?)
+        },
+        false => None,
+    };
+
+    let journal = match &args.journal {
+        Some(path) if !args.dry_run && !args.report => Some(Journal::open(path)?),
+        _ => None,
+    };
+
+    let progress = {
+        use std::io::IsTerminal;
+        (!args.no_progress && verbosity >= 0 && std::io::stderr().is_terminal())
+            .then(|| std::sync::Arc::new(Progress::new()))
+    };
+    output.progress = progress.clone().map(ProgressLine::new);
+
+    let config = builder.hash_cache(hash_cache).journal(journal).progress(progress).build();
+
+    let mut errors = ErrorTally::default();
+    for paths in run_paths {
+        if interrupted().is_some() {
+            break;
         }
+        errors += run(paths, &config, &output);
     }
+    output.flush();
 
-    for paths in run_paths {
-        run(paths, &config).map_err(|_| 1)?;
+    if let Some(cache) = config.hash_cache()
+        && let Err(error) = cache.save() {
+        if verbosity >= 0 {
+            output.eprintln(format_args!("{}", error));
+        }
+        errors.record(&error);
     }
+    print_error_tally(&errors, &output);
 
-    Ok(())
+    if interrupted().is_some() && verbosity >= 0 {
+        output.eprintln(format_args!("Interrupted; stopped before processing all files"));
+    }
+
+    Ok(errors)
+}
+
+
+
+/// perform a full run
+/// returns the tally of errors, which the caller prints
+fn run(
+    pwmds: Vec<PathWithMetadata>,
+    cfg: &Config,
+    output: &Output,
+) -> ErrorTally {
+    let on_event = |event: Event| output.event(event, cfg);
+    if output.json_output {
+        output.print(|out| {
+            write!(out, "{{\"event\":\"scan_started\",\"targets\":[")?;
+            for (i, pwmd) in pwmds.iter().enumerate() {
+                write!(out, "{}{}", if i == 0 { "" } else { "," }, JsonStr(&pwmd.path.to_string_lossy()))?;
+            }
+            writeln!(out, "]}}")
+        });
+    }
+
+    let (registry, scan_errors) = scan(pwmds, cfg, &on_event);
+
+    if output.verbosity >= 0 && !output.machine_output() {
+        output.print(|out| writeln!(out,
+            "Considering {} total files for duplicates",
+            registry.values().map(|files| files.len()).sum::<usize>()));
+    }
+
+    let mut summary = deduplicate(registry, cfg, &on_event);
+    summary.errors += scan_errors;
+
+    if output.json_output {
+        output.print(|out| writeln!(out,
+            "{{\"event\":\"summary\",\"mode\":\"{}\",\"dry_run\":{},\"report\":{},\"interrupted\":{},\"files_considered\":{},\"inodes_starting\":{},\"inodes_linked\":{},\"link_groups_started\":{},\"bytes_freed\":{},\"errors\":{}}}",
+            cfg.link_mode().name(),
+            cfg.dry_run(),
+            cfg.report(),
+            summary.interrupted,
+            summary.files_considered,
+            summary.inodes_starting,
+            summary.inodes_linked,
+            summary.link_groups_started,
+            summary.bytes_freed,
+            error_tally_json(&summary.errors),
+        ));
+    } else if output.verbosity >= 0 && !output.raw_output_only && cfg.report() {
+        output.print(|out| writeln!(out,
+            "Found {}/{} ({:.2}%) total files to be duplicates; linking would free {:.2} MiB of storage space",
+            summary.inodes_linked,
+            summary.inodes_starting,
+            100.0 * summary.inodes_linked as f32 / summary.inodes_starting as f32,
+            summary.bytes_freed as f32 / 1024.0 / 1024.0,
+        ));
+    } else if output.verbosity >= 0 && !output.raw_output_only {
+        output.print(|out| writeln!(out,
+            "{} {}/{} ({:.2}%) total files freeing {:.2} MiB of storage space",
+            cfg.link_mode().past_tense_capitalized(),
+            summary.inodes_linked,
+            summary.inodes_starting,
+            100.0 * summary.inodes_linked as f32 / summary.inodes_starting as f32,
+            summary.bytes_freed as f32 / 1024.0 / 1024.0,
+        ));
+        if summary.link_groups_started > 0 {
+            output.print(|out| writeln!(out,
+                "Started {} new link groups after kept files reached the link limit",
+                summary.link_groups_started,
+            ));
+        }
+    }
+    summary.errors
+}
+
+/// print the number of errors by category, if any
+fn print_error_tally(errors: &ErrorTally, output: &Output) {
+    if output.verbosity >= 0 && !output.machine_output() && !errors.is_empty() {
+        output.print(|out| writeln!(out, "Encountered {} errors: {}", errors.total(), errors));
+    }
 }
 
 
 
 /// result may be empty; contents each nonempty
 fn obtain_run_targets<'a>(
-    arg_file: Option<&String>,
-    arg_targets: &'a mut Vec<String>,
-    separator: &String,
-    verbosity: i16
-) -> Result<Vec<Vec<&'a String>>, i32> {
+    arg_file: Option<&PathBuf>,
+    arg_targets: &'a mut Vec<std::ffi::OsString>,
+    separator: &std::ffi::OsString,
+    null_delimited: bool,
+) -> Result<Vec<Vec<&'a std::ffi::OsString>>, Error> {
     if let Some(arg_file) = &arg_file {
         if !arg_targets.is_empty() {
-            if verbosity >= 0 {
-                eprintln!("No targets should be provided as cli arguments if arguments are being read from file");
-            }
-            return Err(1);
-        }
-        if let Err(s) = {
-            if *arg_file == "-" {
-                read_lines(std::io::stdin().lock(), arg_targets)
-            } else {
-                read_file_lines(Path::new(&arg_file), arg_targets)
-            }
-        } {
-            if verbosity >= 0 {
-                eprintln!("Error reading file containing targets: {}", s);
-            }
-            return Err(1);
+            return Err(Error::Usage(
+                "No targets should be provided as cli arguments if arguments are being read from file".to_string()
+            ));
         }
-    } else {
-        for target in arg_targets.iter() {
-            if target.contains('\0') {
-                if verbosity >= 0 {
-                    eprintln!("Paths can never contain null byte: {}", target);
-                }
-                return Err(1);
-            }
+        if arg_file.as_os_str() == "-" {
+            read_lines(std::io::stdin().lock(), arg_targets, null_delimited)
+                .map_err(|e| Error::io(Operation::Read, "-", e))?;
+        } else {
+            read_file_lines(arg_file, arg_targets, null_delimited)?;
         }
+    } else if let Some(target) = arg_targets.iter().find(|target| target.as_encoded_bytes().contains(&0)) {
+        return Err(Error::Usage(format!("Paths can never contain null byte: {}", target.to_string_lossy())));
     }
 
-    let mut run_targets = split_slice(arg_targets, &separator);
+    let mut run_targets = split_slice(arg_targets, separator);
     for i in (0..run_targets.len()).rev() {
-        if run_targets[i].len() == 0 {
+        if run_targets[i].is_empty() {
             run_targets.swap_remove(i);
         }
     }
@@ -222,34 +577,25 @@ fn obtain_run_targets<'a>(
 /// result has no symlinks; may be empty; contents each nonempty
 fn obtain_run_paths<T, Y, U>(
     run_targets: T,
-    verbosity: i16
-) -> Result<Vec<Vec<PathWithMetadata>>, i32>
+) -> Result<Vec<Vec<PathWithMetadata>>, Error>
 where
     T: Iterator<Item=Y> + ExactSizeIterator,
     Y: Iterator<Item=U> + ExactSizeIterator,
-    U: AsRef<str>,
+    U: AsRef<Path>,
 {
     let mut run_paths: Vec<Vec<PathWithMetadata>> = Vec::with_capacity(run_targets.len());
     for spaths in run_targets {
         let mut paths = Vec::with_capacity(spaths.len());
         for spath in spaths {
-            let path = Path::new(spath.as_ref()).canonicalize().map_err(|_| {
-                if verbosity >= 1 {
-                    eprintln!("Failed to retrieve absolute path for {}", shlex::try_quote(spath.as_ref()).unwrap());
-                }
-                1
-            })?;
-            let pwmd = PathWithMetadata::new(path).map_err(|s| {
-                if verbosity >= 1 {
-                    eprintln!("{}", s);
-                }
-                1
-            })?;
-            if !pwmd.md().file_type().is_symlink() {
+            // targets themselves are resolved, as given on the command line
+            let path = spath.as_ref().canonicalize()
+                .map_err(|e| Error::io(Operation::Canonicalize, spath.as_ref(), e))?;
+            let pwmd = PathWithMetadata::new(path)?;
+            if !pwmd.md().is_symlink() {
                 paths.push(pwmd);
             }
         }
-        if paths.len() > 0 {
+        if !paths.is_empty() {
             run_paths.push(paths);
         }
     }
@@ -257,253 +603,25 @@ where
 }
 
 
-/// perform a full run
-fn run(
-    pwmds: Vec<PathWithMetadata>,
-    cfg: &Config
-) -> std::io::Result<()> {
-    let mut registry  = Registry::new();
-    for pwmd in pwmds {
-        register(pwmd, &mut registry, cfg);
-    }
-    registry.retain(|_,files| files.len() >= 2);
-
-    if cfg.verbosity >= 0 && !cfg.raw_output_only {
-        println!("Considering {} total files for duplicates",
-            registry.iter().map(|(_,files)| files.len()).sum::<usize>()
-        );
-    }
-
-
-
-    let (printer, tx) = match cfg.verbosity >= 0 || cfg.raw_output_only {
-        true => {
-            let (tx, rx) = std::sync::mpsc::channel::<String>();
-            let t = std::thread::spawn(move || {
-                let mut buf = std::io::BufWriter::new(std::io::stdout().lock());
-                for msg in rx {
-                    write!(&mut buf, "{}", msg).unwrap();
-                }
-            });
-            (Some(t), Some(tx))
-        },
-        false => (None, None)
-    };
-
-    use rayon::prelude::*;
-    let (_, savings, number_ionodes_removed, starting_inode_count) =
-        registry.into_par_iter()
-        .fold(
-            || (
-                String::with_capacity(match cfg.verbosity {
-                    ..0 => 0,
-                    0   => 1024,
-                    1.. => 256,
-                }),
-                0, 0, 0
-            ),
-            |
-                (mut buf, total_savings, total_inodes_linked, total_inodes_starting),
-                (fsize, pwmds)
-            | {
-                let (inodes_linked, inodes_starting) = run_one_size(fsize, &pwmds, cfg, &mut buf);
-                (buf, total_savings + fsize as usize * inodes_linked,
-                 total_inodes_linked + inodes_linked, total_inodes_starting + inodes_starting)
-            }
-        )
-        .reduce(
-            || (String::new(), 0, 0, 0),
-            |
-                (dummy_buf, total_savings, total_inodes_linked, total_inodes_starting),
-                (buf,       savings,       inodes_linked,       inodes_starting)
-            | {
-                if !buf.is_empty() {
-                    if let Some(tx) = &tx {
-                        tx.send(buf).unwrap();
-                    }
-                }
-                (dummy_buf, total_savings + savings,
-                 total_inodes_linked + inodes_linked, total_inodes_starting + inodes_starting)
-            }
-        );
-
-    if cfg.verbosity >= 0 && !cfg.raw_output_only {
-        tx.as_ref().unwrap().send(format!(
-            "Hardlinked {}/{} ({:.2}%) total files freeing {:.2} MiB of storage space\n",
-            number_ionodes_removed,
-            starting_inode_count,
-            100.0 * number_ionodes_removed as f32 / starting_inode_count as f32,
-            savings as f32 / 1024.0 / 1024.0,
-        )).unwrap();
-    }
-
-    drop(tx);
-    if let Some(printer) = printer {
-        printer.join().unwrap();
-    }
-
-    Ok(())
-}
-
-
-
-fn run_one_size(
-    fsize: u64,
-    pwmds: &[PathWithMetadata],
-    cfg: &Config,
-    mut output: impl std::fmt::Write,
-) -> (usize, usize) {
-    let mut by_inode: Vec<SmallVec<[&PathWithMetadata; 1]>>
-        = Vec::with_capacity((pwmds.len() as f64 * 0.8) as usize); // each nonempty
-    let mut inodes: Vec<u64> = Vec::with_capacity(by_inode.capacity());
-    for pwmd in pwmds {
-        let inode: u64 = pwmd.md().st_ino();
-        match inodes.binary_search(&inode) {
-            Ok(i) => {
-                by_inode[i].push(pwmd);
-            },
-            Err(i) => {
-                inodes.insert(i, inode);
-                by_inode.insert(i, smallvec![pwmd]);
-            }
-        }
-    }
-    drop(inodes);
-    by_inode.sort_by(|a,b| b.len().cmp(&a.len())); // descending size order
-
-    let starting_inode_count = by_inode.len();
-
-    // compare each with eachother
-    let mut i = 0;
-    while i < by_inode.len() {
-        let mut j = i+1;
-        while j < by_inode.len() {
-            let (keeps, replaces) = get2mut(&mut by_inode, i, j);
-            if hardlink_all(keeps, replaces, cfg, &mut output) {
-                by_inode.swap_remove(j);
-            } else {
-                j += 1;
-            }
-        }
-        i += 1;
-    }
-
-    let number_ionodes_removed = starting_inode_count - by_inode.len();
-
-    if cfg.verbosity >= 1 && !cfg.raw_output_only && number_ionodes_removed > 0 {
-        write!(output, "Hardlinked {:>3}/{:>3} ({:>6.2}%) files of size {}\n",
-            number_ionodes_removed,
-            starting_inode_count,
-            100.0 * number_ionodes_removed as f32 / starting_inode_count as f32,
-            fsize
-        ).unwrap();
-    }
-    if cfg.verbosity >= 3 && !cfg.raw_output_only {
-        write!(output, "No duplicates found for {:>3} files of size {}\n",
-            starting_inode_count,
-            fsize
-        ).unwrap();
-    }
-    return (number_ionodes_removed, starting_inode_count)
-}
-
-
-
-/// recursively register path or its contents if directory into registry
-/// eprints errors
-pub fn register(
-    pwmd: PathWithMetadata,
-    registry: &mut HashMap<u64, Vec<PathWithMetadata>>,
-    cfg: &Config,
-) {
-    if pwmd.md().file_type().is_symlink() {
-        return;
-    }
-
-    if pwmd.path.is_file() {
-        let size = pwmd.md().st_size();
-        if size >= cfg.min_size {
-            registry.entry(size).or_default().push(pwmd);
-        }
-        return;
-    }
-
-    if pwmd.path.is_dir() { match std::fs::read_dir(&pwmd.path) {
-        Ok(entries) => for entry in entries { match entry {
-            Ok(entry) => match PathWithMetadata::new(entry.path()) {
-                Ok(child_pwmd) => register(child_pwmd, registry, cfg),
-                Err(s) => if cfg.verbosity >= 1 {
-                    eprintln!("{}", s);
-                },
-            },
-            Err(error) => if cfg.verbosity >= 1 {
-                eprintln!(
-                    "Failed to inspect {}: {}",
-                    shlex::try_quote(&pwmd.path.to_string_lossy()).unwrap(),
-                    error);
-            },
-        } },
-        Err(error) => if cfg.verbosity >= 1 {
-            eprintln!(
-                "Failed to read dir {}: {}",
-                shlex::try_quote(&pwmd.path.to_string_lossy()).unwrap(),
-                error);
-        },
-    } }
-}
-
-
-
-pub struct PathWithMetadata {
-    pub path: PathBuf,
-    md: std::cell::RefCell<std::fs::Metadata>,
-}
-impl PathWithMetadata {
-    pub fn new(path: PathBuf) -> Result<Self, String>{
-        let md = std::cell::RefCell::new(Self::get_md(&path)?);
-        Ok(PathWithMetadata{ path, md })
-    }
-    pub fn md<'a>(&'a self) -> std::cell::Ref<'a, std::fs::Metadata> {
-        self.md.borrow()
-    }
-    pub fn reset_md(&self) -> Result<(), String> {
-        *self.md.borrow_mut() = Self::get_md(&self.path)?;
-        Ok(())
-    }
-    fn get_md(path: &PathBuf) -> Result<std::fs::Metadata, String> {
-        Ok(std::fs::symlink_metadata(path)
-            .map_err(|e| format!(
-                "Failed to retrive metadata for {}: {}",
-                shlex::try_quote(&path.to_string_lossy()).unwrap(),
-                e
-            ))?)
-    }
-
-}
-impl AsRef<PathBuf> for PathWithMetadata {
-    fn as_ref(&self) -> &PathBuf {
-        return &self.path;
-    }
-}
-impl AsRef<Path> for PathWithMetadata {
-    fn as_ref(&self) -> &Path {
-        return &self.path.as_ref();
-    }
+fn parse_glob(s: &str) -> Result<glob::Pattern, glob::PatternError> {
+    glob::Pattern::new(s)
 }
 
 
 
 /// return whether or not user gave confirmation
-pub fn prompt_confirm<'a, T, Y>(run_targets: &[T]) -> std::io::Result<bool>
-where T: std::borrow::Borrow<[Y]>, Y: AsRef<str> {
+/// paths that are not valid unicode are shown lossily
+pub fn prompt_confirm<T, Y>(run_targets: &[T]) -> std::io::Result<bool>
+where T: std::borrow::Borrow<[Y]>, Y: AsRef<std::ffi::OsStr> {
     {
         let mut stdout_buffer = std::io::BufWriter::new(std::io::stdout().lock());
         writeln!(&mut stdout_buffer, "Are you sure you want to link all duplicates in each of these sets of targets?")?;
         for spaths in run_targets {
-            writeln!(&mut stdout_buffer, "  {}", shlex::try_join(spaths.borrow().iter().map(|s| s.as_ref())).unwrap())?;
+            let spaths: Vec<_> = spaths.borrow().iter().map(|s| s.as_ref().to_string_lossy()).collect();
+            writeln!(&mut stdout_buffer, "  {}", shlex::try_join(spaths.iter().map(|s| s.as_ref())).unwrap())?;
         }
         write!(&mut stdout_buffer, "> ")?;
-        stdout_buffer.flush().unwrap_or_else(|_| ());
+        stdout_buffer.flush().unwrap_or(());
     }
 
     let mut response = String::new();
@@ -512,220 +630,32 @@ where T: std::borrow::Borrow<[Y]>, Y: AsRef<str> {
     Ok(response.to_lowercase().starts_with("y"))
 }
 
+/// null_delimited: lines are terminated by null bytes instead of newlines
+/// lines are taken as raw bytes, so paths need not be valid unicode
+/// otherwise, lines are terminated by \n or \r\n
 pub fn read_lines(
     reader: impl BufRead,
-    dest: &mut Vec<String>
-) -> Result<(), String> {
-    for line in reader.lines() {
-        dest.push(line.map_err(|e| format!("Error reading line: {}", e))?);
+    dest: &mut Vec<std::ffi::OsString>,
+    null_delimited: bool
+) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStringExt;
+    for line in reader.split(if null_delimited { b'\0' } else { b'\n' }) {
+        let mut line = line?;
+        if !null_delimited && line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        dest.push(std::ffi::OsString::from_vec(line));
     }
     Ok(())
 }
 
 pub fn read_file_lines(
     path: &Path,
-    dest: &mut Vec<String>
-) -> Result<(), String> {
-    if !path.is_file() {
-        return Err(format!("File does not exist or is not a normal file ({})", shlex::try_quote(&path.to_string_lossy()).unwrap()));
-    }
-    let reader = std::io::BufReader::new(std::fs::File::open(path).map_err(
-        |e| format!("Could not open {}: {}", shlex::try_quote(&path.to_string_lossy()).unwrap(), e)
-    )?);
-    Ok(read_lines(reader, dest)?)
-}
-
-
-pub fn check_all_same_device(
-    pwmds: &[PathWithMetadata]
-) -> Result<(), String> {
-    if pwmds.len() <= 1 {
-        return Ok(())
-    }
-    let mut by_dev: HashMap<u64, Vec<&PathWithMetadata>> = Default::default();
-    for pwmd in pwmds.iter() {
-        by_dev.entry(pwmd.md().st_dev()).or_default().push(pwmd);
-    }
-    if by_dev.len() <= 1 {
-        return Ok(());
-    }
-    let mut lines = Vec::with_capacity(1+by_dev.len());
-    lines.push(String::from("Device ids must all be the same; got paths on different devices:"));
-    lines.extend(by_dev.into_iter().map(|(dev,pwmds)| {
-        if pwmds.len() == 1 {
-            format!("  Device {}: {} path: {}", dev, pwmds.len(), &shlex::try_quote(&pwmds[0].path.to_string_lossy()).unwrap())
-        } else {
-            format!("  Device {}: {} paths", dev, pwmds.len())
-        }
-    }));
-    Err(lines.join("\n"))
-}
-
-
-/// get two mutable references in an array
-/// expects correct inputs
-pub fn get2mut<'a, T>(
-    v: &'a mut [T],
-    i: usize, j: usize
-) -> (&'a mut T, &'a mut T) {
-    let (left, right) = v.split_at_mut(j);
-    (&mut left[i], &mut right[0])
-}
-
-
-pub fn hardlink(
-    keep: &PathWithMetadata,
-    replace: &PathWithMetadata
-) -> Result<(), String> {
-    std::fs::remove_file(&replace.path).map_err(|_| "Failed to remove for hardlinking")?;
-    std::fs::hard_link(&keep.path, &replace.path).map_err(|_| {
-        match std::fs::copy(&keep.path, &replace.path) {
-            Ok(_) => "Failed to hardlink (copied instead)",
-            Err(_) => "Failed to hardlink or copy" // awful scenario but i believe it is impossible since i don't see how you could remove a file yet not create one in its place
-        }
-    })?;
-    replace.reset_md()?;
-    Ok(())
-}
-
-/// returns whether linking was done
-/// eprints errors
-fn hardlink_all<'a, 'b, T>(
-    keeps: &'a mut SmallVec<T>,
-    replaces: &'a mut SmallVec<T>,
-    cfg: &Config,
-    mut output: impl std::fmt::Write,
-) -> bool
-where T: smallvec::Array<Item=&'b PathWithMetadata>,
-{
-    if !cmp(&replaces.first().unwrap().path, &keeps.first().unwrap().path).unwrap_or(false) {
-        return false;
-    }
-    for replace in replaces.into_iter() {
-        let keep = keeps.first().unwrap();
-        if !cfg.dry_run {
-            if let Err(msg) = hardlink(keep, replace) {
-                if cfg.verbosity >= 0 {
-                    let mut s = String::new();
-                    write_pair(&mut s, &keep.path.to_string_lossy(), &replace.path.to_string_lossy(), cfg).unwrap();
-                    eprintln!("{}: {}", msg, s);
-                }
-                continue; // path no longer valid
-            }
-        }
-        if cfg.verbosity >= 2 || cfg.raw_output_only {
-            if !cfg.raw_output_only {
-                write!(&mut output, "hardlinked\t").unwrap();
-            }
-            write_pair(&mut output, &keep.path.to_string_lossy(), &replace.path.to_string_lossy(), cfg).unwrap();
-            write!(&mut output, "\n").unwrap();
-        }
-        keeps.push(replace);
-    }
-    true
-}
-
-
-pub fn write_pair(
-    mut buf: impl std::fmt::Write,
-    f1s: &str,
-    f2s: &str,
-    cfg: &Config
-) -> std::fmt::Result {
-    if cfg.raw_output_only {
-        return write!(buf,
-            "{}\t{}",
-            f1s,
-            f2s,
-        )
-    }
-
-    if cfg.no_brace_output {
-        return write!(buf,
-            "{}  {}",
-            shlex::try_quote(&f1s).unwrap(),
-            shlex::try_quote(&f2s).unwrap()
-        )
-    }
-
-    let prefix = common_prefix(&f1s, &f2s);
-    let suffix = common_suffix(&f1s, &f2s);
-    let prefixlong = prefix.len() > 2;
-    let suffixlong = suffix.len() > 2;
-    if prefixlong && suffixlong {
-        write!(buf,
-            "{}{{{},{}}}{}",
-            shlex::try_quote(prefix).unwrap(),
-            shlex::try_quote(&f1s[ prefix.len()..std::cmp::max(prefix.len(), f1s.len()-suffix.len()) ]).unwrap(),
-            shlex::try_quote(&f2s[ prefix.len()..std::cmp::max(prefix.len(), f2s.len()-suffix.len()) ]).unwrap(),
-            shlex::try_quote(suffix).unwrap()
-        )
-    } else if prefixlong {
-        write!(buf,
-            "{}{{{},{}}}",
-            shlex::try_quote(prefix).unwrap(),
-            shlex::try_quote(&f1s[prefix.len()..]).unwrap(),
-            shlex::try_quote(&f2s[prefix.len()..]).unwrap()
-        )
-    } else if suffixlong {
-        write!(buf,
-            "{{{},{}}}{}",
-            shlex::try_quote(&f1s[..f1s.len()-suffix.len()]).unwrap(),
-            shlex::try_quote(&f2s[..f2s.len()-suffix.len()]).unwrap(),
-            shlex::try_quote(suffix).unwrap(),
-        )
-    } else {
-        write!(buf,
-            "{} <-> {}",
-            shlex::try_quote(&f1s).unwrap(),
-            shlex::try_quote(&f2s).unwrap()
-        )
-    }
-}
-
-
-/// check equality of contents of two paths to files
-/// does not check sizes
-pub fn cmp(f1: impl AsRef<Path>, f2: impl AsRef<Path>) -> std::io::Result<bool> {
-    cmp_read(std::fs::File::open(f1)?, std::fs::File::open(f2)?)
-}
-
-/// check equality of contents of two open files
-pub fn cmp_read(mut f1: impl Read, mut f2: impl Read) -> std::io::Result<bool> {
-    let buff1: &mut [u8] = &mut [0; 1024];
-    let buff2: &mut [u8] = &mut [0; 1024];
-    loop {
-        let l1 = f1.read(buff1)?;
-        let l2 = f2.read(buff2)?;
-        if l1 != l2 { // different sizes
-            return Ok(false);
-        }
-        if l1 == 0 { // end of both files
-            return Ok(true);
-        }
-        if &buff1[0..l1] != &buff2[0..l2] { // compare data
-            return Ok(false);
-        }
-    }
-}
-
-
-pub fn common_prefix<'a>(s1: &'a str, s2: &'a str) -> &'a str {
-    let len = s1
-        .chars()
-        .zip(s2.chars())
-        .take_while(|(char1, char2)| char1 == char2)
-        .count();
-    &s1[..len]
-}
-pub fn common_suffix<'a>(s1: &'a str, s2: &'a str) -> &'a str {
-    let len = s1
-        .chars()
-        .rev()
-        .zip(s2.chars().rev())
-        .take_while(|(char1, char2)| char1 == char2)
-        .count();
-    &s1[s1.len() - len..]
+    dest: &mut Vec<std::ffi::OsString>,
+    null_delimited: bool
+) -> Result<(), Error> {
+    let reader = std::io::BufReader::new(std::fs::File::open(path).map_err(|e| Error::io(Operation::Open, path, e))?);
+    read_lines(reader, dest, null_delimited).map_err(|e| Error::io(Operation::Read, path, e))
 }
 
 
@@ -751,13 +681,28 @@ pub fn split_slice<'a, T: std::cmp::PartialEq>(input: &'a [T], delimiter: &T) ->
 }
 
 
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
     #[test]
     pub fn test_split_slice() {
-        let v: Vec<_> = vec![";", ";", ";"].into_iter().map(|s| s.to_string()).collect();
-        let res = split_slice(&v[..], &";".to_string());
+        let v: Vec<_> = vec![";", ";", ";"].into_iter().map(std::ffi::OsString::from).collect();
+        let res = split_slice(&v[..], &std::ffi::OsString::from(";"));
         assert_eq!(res.len(), 2)
     }
+    #[test]
+    pub fn test_absolute_min_size() {
+        assert_eq!(s_value_absolute_min_size!().parse::<u64>().unwrap(), lndups::config::ABSOLUTE_MIN_SIZE);
+    }
+    #[test]
+    pub fn test_read_lines_null_delimited() {
+        let mut v = Vec::new();
+        read_lines(&b"a\nb\0c\xff\0"[..], &mut v, true).unwrap();
+        use std::os::unix::ffi::OsStrExt;
+        assert_eq!(v, vec![std::ffi::OsStr::new("a\nb"), std::ffi::OsStr::from_bytes(b"c\xff")]);
+        v.clear();
+        read_lines(&b"a\r\nb\xff\n"[..], &mut v, false).unwrap();
+        assert_eq!(v, vec![std::ffi::OsStr::new("a"), std::ffi::OsStr::from_bytes(b"b\xff")]);
+    }
 }
@@ -0,0 +1,554 @@
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+
+use lndups::{Config, Error, ErrorTally, Event, Phase, Progress, ProgressSnapshot};
+
+
+
+/// how results are shown; the library only reports events, all printing is done here
+pub struct Output {
+    /// below 0 suppresses output, above 0 adds detail
+    pub verbosity: i16,
+    pub raw_output_only: bool,
+    pub json_output: bool,
+    pub null_delimited: bool,
+    pub no_brace_output: bool,
+    /// cleared before writing to stdout
+    pub progress: Option<ProgressLine>,
+    stdout: std::sync::Mutex<std::io::BufWriter<std::io::Stdout>>,
+}
+
+impl Output {
+    pub fn new(
+        verbosity: i16,
+        raw_output_only: bool,
+        json_output: bool,
+        null_delimited: bool,
+        no_brace_output: bool,
+    ) -> Self {
+        Output {
+            verbosity,
+            raw_output_only,
+            json_output,
+            null_delimited,
+            no_brace_output,
+            progress: None,
+            stdout: std::sync::Mutex::new(std::io::BufWriter::new(std::io::stdout())),
+        }
+    }
+
+    /// whether output is meant for programs rather than people
+    pub fn machine_output(&self) -> bool {
+        self.raw_output_only || self.json_output
+    }
+    /// terminator of raw output lines
+    pub fn line_terminator(&self) -> char {
+        if self.raw_output_only && self.null_delimited { '\0' } else { '\n' }
+    }
+
+    /// write to stdout, buffered unless progress is drawn
+    pub fn print(&self, write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>) {
+        let mut stdout = self.stdout.lock().unwrap();
+        match &self.progress {
+            Some(progress) => progress.cleared(|| {
+                write(&mut *stdout)?;
+                stdout.flush() // before progress is redrawn
+            }),
+            None => write(&mut *stdout),
+        }.unwrap();
+    }
+    /// write a line to stderr, clearing progress first
+    pub fn eprintln(&self, line: std::fmt::Arguments) {
+        let write = || {
+            let mut stderr = std::io::stderr().lock();
+            let _ = writeln!(stderr, "{}", line);
+        };
+        match &self.progress {
+            Some(progress) => progress.cleared(write),
+            None => write(),
+        }
+    }
+    pub fn flush(&self) {
+        self.stdout.lock().unwrap().flush().unwrap();
+    }
+
+    /// show an event of scan, deduplicate or undo as configured
+    pub fn event(&self, event: Event, cfg: &Config) {
+        match event {
+            Event::Error(error) => {
+                if self.json_output {
+                    self.print(|out| {
+                        write!(out, "{{\"event\":\"error\",\"path\":{},",
+                            error.path().map_or("null".to_string(), |path| JsonStr(&path.to_string_lossy()).to_string()))?;
+                        write_error_json(out, error)?;
+                        writeln!(out, "}}")
+                    });
+                } else if self.verbosity >= 1 {
+                    self.eprintln(format_args!("{}", error));
+                }
+            },
+            Event::SkippedMountPoint(path) => if self.verbosity >= 1 {
+                self.eprintln(format_args!("Skipping mount point {}", shlex::try_quote(&path.to_string_lossy()).unwrap()));
+            },
+            Event::GroupFound { size, inodes, paths } => {
+                if self.json_output {
+                    self.print(|out| write_group_found_json(out, size, inodes, paths));
+                } else if cfg.report() {
+                    self.print(|out| write_report(out, size, inodes, paths, self));
+                }
+            },
+            Event::Linked { size, keep, replace, bytes_deduped } => {
+                if self.json_output {
+                    self.print(|out| {
+                        write!(out,
+                            "{{\"event\":\"link_performed\",\"mode\":\"{}\",\"dry_run\":{},\"size\":{},\"keep\":{},\"replace\":{}",
+                            cfg.link_mode().name(),
+                            cfg.dry_run(),
+                            size,
+                            JsonStr(&keep.to_string_lossy()),
+                            JsonStr(&replace.to_string_lossy()),
+                        )?;
+                        if let Some(n) = bytes_deduped {
+                            write!(out, ",\"bytes_deduped\":{}", n)?;
+                        }
+                        writeln!(out, "}}")
+                    });
+                } else if self.verbosity >= 2 || self.raw_output_only {
+                    self.print(|out| {
+                        if !self.raw_output_only {
+                            write!(out, "{}\t", cfg.link_mode().past_tense())?;
+                        }
+                        write_pair(&mut *out, keep, replace, self)?;
+                        if let Some(n) = bytes_deduped && !self.raw_output_only {
+                            write!(out, " ({} bytes)", n)?;
+                        }
+                        write!(out, "{}", self.line_terminator())
+                    });
+                }
+            },
+            Event::LinkFailed { size, keep, replace, error } => {
+                if self.json_output {
+                    self.print(|out| {
+                        write!(out,
+                            "{{\"event\":\"link_failed\",\"mode\":\"{}\",\"size\":{},\"keep\":{},\"replace\":{},",
+                            cfg.link_mode().name(),
+                            size,
+                            JsonStr(&keep.to_string_lossy()),
+                            JsonStr(&replace.to_string_lossy()),
+                        )?;
+                        write_error_json(out, error)?;
+                        writeln!(out, "}}")
+                    });
+                } else if self.verbosity >= 0 {
+                    self.eprintln(format_args!("{}: {}", error, self.pair(keep, replace)));
+                }
+            },
+            Event::LinkLimitReached { size, keep, new_keep } => {
+                if self.json_output {
+                    self.print(|out| writeln!(out,
+                        "{{\"event\":\"link_limit_reached\",\"size\":{},\"keep\":{},\"new_keep\":{}}}",
+                        size,
+                        JsonStr(&keep.to_string_lossy()),
+                        JsonStr(&new_keep.to_string_lossy()),
+                    ));
+                } else if self.verbosity >= 1 {
+                    self.eprintln(format_args!(
+                        "Link limit reached for {}, keeping {} for the remaining duplicates",
+                        shlex::try_quote(&keep.to_string_lossy()).unwrap(),
+                        shlex::try_quote(&new_keep.to_string_lossy()).unwrap()));
+                }
+            },
+            Event::SizeDone { size, inodes_starting, inodes_linked } => {
+                if self.verbosity >= 1 && !self.machine_output() && !cfg.report() && inodes_linked > 0 {
+                    self.print(|out| writeln!(out, "{} {:>3}/{:>3} ({:>6.2}%) files of size {}",
+                        cfg.link_mode().past_tense_capitalized(),
+                        inodes_linked,
+                        inodes_starting,
+                        100.0 * inodes_linked as f32 / inodes_starting as f32,
+                        size
+                    ));
+                }
+                if self.verbosity >= 3 && !self.machine_output() {
+                    self.print(|out| writeln!(out, "No duplicates found for {:>3} files of size {}",
+                        inodes_starting,
+                        size
+                    ));
+                }
+            },
+            Event::MalformedJournalLine { journal, line } => {
+                if self.json_output {
+                    self.print(|out| writeln!(out,
+                        "{{\"event\":\"malformed_journal_line\",\"journal\":{},\"line\":{}}}",
+                        JsonStr(&journal.to_string_lossy()),
+                        line,
+                    ));
+                } else if self.verbosity >= 0 {
+                    self.eprintln(format_args!("Ignoring malformed line {} of journal {}", line, shlex::try_quote(&journal.to_string_lossy()).unwrap()));
+                }
+            },
+            Event::Unlinked { keep, replace } => {
+                if self.json_output {
+                    self.print(|out| writeln!(out,
+                        "{{\"event\":\"unlinked\",\"dry_run\":{},\"keep\":{},\"replace\":{}}}",
+                        cfg.dry_run(),
+                        JsonStr(&keep.to_string_lossy()),
+                        JsonStr(&replace.to_string_lossy()),
+                    ));
+                } else if self.verbosity >= 2 || self.raw_output_only {
+                    self.print(|out| {
+                        if !self.raw_output_only {
+                            write!(out, "unlinked\t")?;
+                        }
+                        write_pair(&mut *out, keep, replace, self)?;
+                        write!(out, "{}", self.line_terminator())
+                    });
+                }
+            },
+            Event::NotLinked { keep, replace } => {
+                if self.json_output {
+                    self.print(|out| writeln!(out,
+                        "{{\"event\":\"not_linked\",\"keep\":{},\"replace\":{}}}",
+                        JsonStr(&keep.to_string_lossy()),
+                        JsonStr(&replace.to_string_lossy()),
+                    ));
+                } else if self.verbosity >= 1 {
+                    self.eprintln(format_args!("Not linked anymore, skipping: {}", self.pair(keep, replace)));
+                }
+            },
+            Event::UnlinkFailed { keep, replace, error } => {
+                if self.json_output {
+                    self.print(|out| {
+                        write!(out,
+                            "{{\"event\":\"unlink_failed\",\"keep\":{},\"replace\":{},",
+                            JsonStr(&keep.to_string_lossy()),
+                            JsonStr(&replace.to_string_lossy()),
+                        )?;
+                        write_error_json(out, error)?;
+                        writeln!(out, "}}")
+                    });
+                } else if self.verbosity >= 0 {
+                    self.eprintln(format_args!("{}: {}", error, self.pair(keep, replace)));
+                }
+            },
+        }
+    }
+
+    fn pair(&self, f1: &Path, f2: &Path) -> String {
+        let mut buf = Vec::new();
+        write_pair(&mut buf, f1, f2, self).unwrap();
+        String::from_utf8_lossy(&buf).into_owned()
+    }
+}
+
+
+/// write a set of identical files as a JSON group_found event
+pub fn write_group_found_json(
+    buf: &mut dyn Write,
+    fsize: u64,
+    inodes: usize,
+    paths: &[&Path],
+) -> std::io::Result<()> {
+    write!(buf, "{{\"event\":\"group_found\",\"size\":{},\"inodes\":{},\"paths\":[", fsize, inodes)?;
+    for (i, path) in paths.iter().enumerate() {
+        write!(buf, "{}{}", if i == 0 { "" } else { "," }, JsonStr(&path.to_string_lossy()))?;
+    }
+    writeln!(buf, "]}}")
+}
+
+
+/// write the "error", "operation" and "errno" fields of a JSON event
+/// operation and errno are null if error is not a system error
+pub fn write_error_json(buf: &mut dyn Write, error: &Error) -> std::io::Result<()> {
+    write!(buf, "\"error\":{},\"operation\":{},\"errno\":{}",
+        JsonStr(&error.to_string()),
+        error.operation().map_or("null".to_string(), |operation| format!("\"{}\"", operation.name())),
+        error.raw_os_error().map_or("null".to_string(), |errno| errno.to_string()),
+    )
+}
+
+/// a JSON object with the number of errors of each category
+pub fn error_tally_json(errors: &ErrorTally) -> String {
+    let mut result = String::from("{");
+    for (i, (category, count)) in errors.iter().enumerate() {
+        result.push_str(&format!("{}\"{}\":{}", if i == 0 { "" } else { "," }, category, count));
+    }
+    result.push('}');
+    result
+}
+
+
+/// displays as a JSON string literal
+pub struct JsonStr<'a>(pub &'a str);
+impl std::fmt::Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use std::fmt::Write;
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+
+/// write a set of identical files
+pub fn write_report(
+    buf: &mut dyn Write,
+    fsize: u64,
+    inodes: usize,
+    paths: &[&Path],
+    output: &Output,
+) -> std::io::Result<()> {
+    if output.raw_output_only {
+        use std::os::unix::ffi::OsStrExt;
+        for path in paths {
+            buf.write_all(path.as_os_str().as_bytes())?;
+            write!(buf, "{}", output.line_terminator())?;
+        }
+        return write!(buf, "{}", output.line_terminator());
+    }
+    writeln!(buf, "{} files in {} inodes of size {}:", paths.len(), inodes, fsize)?;
+    for path in paths {
+        writeln!(buf, "  {}", shlex::try_quote(&path.to_string_lossy()).unwrap())?;
+    }
+    Ok(())
+}
+
+
+pub fn write_pair(
+    mut buf: impl Write,
+    f1: &Path,
+    f2: &Path,
+    output: &Output,
+) -> std::io::Result<()> {
+    if output.raw_output_only { // exact bytes, so paths can be fed to other programs
+        use std::os::unix::ffi::OsStrExt;
+        buf.write_all(f1.as_os_str().as_bytes())?;
+        write!(buf, "{}", if output.null_delimited { '\0' } else { '\t' })?;
+        return buf.write_all(f2.as_os_str().as_bytes());
+    }
+
+    let (f1s, f2s) = (&*f1.to_string_lossy(), &*f2.to_string_lossy());
+
+    if output.no_brace_output {
+        return write!(buf,
+            "{}  {}",
+            shlex::try_quote(f1s).unwrap(),
+            shlex::try_quote(f2s).unwrap()
+        )
+    }
+
+    let prefix = common_prefix(f1s, f2s);
+    let suffix = common_suffix(f1s, f2s);
+    let prefixlong = prefix.len() > 2;
+    let suffixlong = suffix.len() > 2;
+    if prefixlong && suffixlong {
+        write!(buf,
+            "{}{{{},{}}}{}",
+            shlex::try_quote(prefix).unwrap(),
+            shlex::try_quote(&f1s[ prefix.len()..std::cmp::max(prefix.len(), f1s.len()-suffix.len()) ]).unwrap(),
+            shlex::try_quote(&f2s[ prefix.len()..std::cmp::max(prefix.len(), f2s.len()-suffix.len()) ]).unwrap(),
+            shlex::try_quote(suffix).unwrap()
+        )
+    } else if prefixlong {
+        write!(buf,
+            "{}{{{},{}}}",
+            shlex::try_quote(prefix).unwrap(),
+            shlex::try_quote(&f1s[prefix.len()..]).unwrap(),
+            shlex::try_quote(&f2s[prefix.len()..]).unwrap()
+        )
+    } else if suffixlong {
+        write!(buf,
+            "{{{},{}}}{}",
+            shlex::try_quote(&f1s[..f1s.len()-suffix.len()]).unwrap(),
+            shlex::try_quote(&f2s[..f2s.len()-suffix.len()]).unwrap(),
+            shlex::try_quote(suffix).unwrap(),
+        )
+    } else {
+        write!(buf,
+            "{} <-> {}",
+            shlex::try_quote(f1s).unwrap(),
+            shlex::try_quote(f2s).unwrap()
+        )
+    }
+}
+
+
+pub fn common_prefix<'a>(s1: &'a str, s2: &'a str) -> &'a str {
+    let len = s1
+        .chars()
+        .zip(s2.chars())
+        .take_while(|(char1, char2)| char1 == char2)
+        .count();
+    &s1[..len]
+}
+pub fn common_suffix<'a>(s1: &'a str, s2: &'a str) -> &'a str {
+    let len = s1
+        .chars()
+        .rev()
+        .zip(s2.chars().rev())
+        .take_while(|(char1, char2)| char1 == char2)
+        .count();
+    &s1[s1.len() - len..]
+}
+
+
+
+
+/// draws a Progress on a single line of stderr from a background thread, while it is not idle
+pub struct ProgressLine {
+    shared: std::sync::Arc<ProgressLineShared>,
+    renderer: Option<std::thread::JoinHandle<()>>,
+}
+
+struct ProgressLineShared {
+    progress: std::sync::Arc<Progress>,
+    /// whether the line is drawn; held while writing to stderr
+    shown: std::sync::Mutex<bool>,
+    stop: AtomicBool,
+}
+
+impl ProgressLine {
+    const INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+    /// starts the thread drawing progress
+    pub fn new(progress: std::sync::Arc<Progress>) -> Self {
+        let shared = std::sync::Arc::new(ProgressLineShared {
+            progress,
+            shown: std::sync::Mutex::new(false),
+            stop: AtomicBool::new(false),
+        });
+        let renderer = {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                while !shared.stop.load(Relaxed) {
+                    std::thread::park_timeout(Self::INTERVAL);
+                    shared.draw();
+                }
+            })
+        };
+        ProgressLine { shared, renderer: Some(renderer) }
+    }
+    /// clear the line so other output starts at its beginning; redrawn on the next interval
+    pub fn clear(&self) {
+        self.shared.clear(&mut self.shared.shown.lock().unwrap());
+    }
+    /// clear the line and call f before it can be redrawn
+    pub fn cleared<R>(&self, f: impl FnOnce() -> R) -> R {
+        let mut shown = self.shared.shown.lock().unwrap();
+        self.shared.clear(&mut shown);
+        f()
+    }
+}
+impl Drop for ProgressLine {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Relaxed);
+        if let Some(renderer) = self.renderer.take() {
+            renderer.thread().unpark();
+            let _ = renderer.join();
+        }
+        self.clear();
+    }
+}
+
+impl ProgressLineShared {
+    fn clear(&self, shown: &mut bool) {
+        if *shown {
+            let mut stderr = std::io::stderr().lock();
+            let _ = write!(stderr, "\r\x1b[K");
+            let _ = stderr.flush();
+            *shown = false;
+        }
+    }
+    fn draw(&self) {
+        let mut shown = self.shown.lock().unwrap();
+        let Some(line) = progress_line(&self.progress.snapshot()) else {
+            return self.clear(&mut shown);
+        };
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r{}\x1b[K", line);
+        let _ = stderr.flush();
+        *shown = true;
+    }
+}
+
+/// nothing while idle
+fn progress_line(snapshot: &ProgressSnapshot) -> Option<String> {
+    let scanned = format!("Scanned {} files", snapshot.files_scanned);
+    match snapshot.phase {
+        Phase::Idle => None,
+        Phase::Scanning => Some(scanned),
+        Phase::Deduplicating => {
+            let mut line = format!("{} | read {:.2} MiB | groups {}/{} | links {}",
+                scanned,
+                snapshot.bytes_read as f64 / 1024.0 / 1024.0,
+                snapshot.groups_done,
+                snapshot.groups_total,
+                snapshot.links_made,
+            );
+            if let Some(eta) = snapshot.eta {
+                line.push_str(&format!(" | ETA {}", format_duration(eta)));
+            }
+            Some(line)
+        },
+    }
+}
+
+/// ex: 1h02m, 3m05s, 12s
+pub fn format_duration(d: std::time::Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        3600.. => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+        60.. => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}s", secs),
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    #[test]
+    pub fn test_json_str() {
+        assert_eq!(JsonStr("a\"b\\c\nd\u{1}").to_string(), "\"a\\\"b\\\\c\\nd\\u0001\"");
+    }
+    #[test]
+    pub fn test_write_error_json() {
+        let mut buf = Vec::new();
+        let error = Error::io(lndups::Operation::Stat, "/a", std::io::Error::from_raw_os_error(libc::EACCES));
+        write_error_json(&mut buf, &error).unwrap();
+        let json = String::from_utf8(buf).unwrap();
+        assert!(json.ends_with(&format!(",\"operation\":\"stat\",\"errno\":{}", libc::EACCES)));
+        let mut tally = ErrorTally::default();
+        assert_eq!(error_tally_json(&tally), "{}");
+        tally.record(&error);
+        assert_eq!(error_tally_json(&tally), "{\"stat\":1}");
+    }
+    #[test]
+    pub fn test_raw_output_keeps_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+        let output = Output::new(0, true, false, true, false);
+        let bad = Path::new(std::ffi::OsStr::from_bytes(b"/bad\xffname"));
+        let mut buf = Vec::new();
+        write_pair(&mut buf, Path::new("/a"), bad, &output).unwrap();
+        assert_eq!(buf, b"/a\0/bad\xffname");
+        let mut buf = Vec::new();
+        write_report(&mut buf, 1, 2, &[Path::new("/a"), bad], &output).unwrap();
+        assert_eq!(buf, b"/a\0/bad\xffname\0\0");
+    }
+    #[test]
+    pub fn test_format_duration() {
+        assert_eq!(format_duration(std::time::Duration::from_secs(12)), "12s");
+        assert_eq!(format_duration(std::time::Duration::from_secs(185)), "3m05s");
+        assert_eq!(format_duration(std::time::Duration::from_secs(3720)), "1h02m");
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering::Relaxed};
+
+
+
+/// progress of a run, updated by scan and deduplicate for the caller to display
+/// counters are reset when scanning starts
+#[derive(Default)]
+pub struct Progress {
+    phase: AtomicU8,
+    files_scanned: AtomicU64,
+    bytes_read: AtomicU64,
+    groups_done: AtomicU64,
+    groups_total: AtomicU64,
+    files_done: AtomicU64,
+    files_total: AtomicU64,
+    links_made: AtomicU64,
+    /// when deduplicating started, for the ETA
+    started: std::sync::Mutex<Option<std::time::Instant>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Idle,
+    Scanning,
+    Deduplicating,
+}
+
+/// the counters of a Progress at one point in time
+#[derive(Clone, Copy, Debug)]
+pub struct ProgressSnapshot {
+    pub phase: Phase,
+    pub files_scanned: u64,
+    /// bytes read to hash or compare contents
+    pub bytes_read: u64,
+    /// sizes deduplicated, of groups_total
+    pub groups_done: u64,
+    pub groups_total: u64,
+    pub links_made: u64,
+    /// time left deduplicating, extrapolated from the files done so far
+    pub eta: Option<std::time::Duration>,
+}
+
+const IDLE: u8 = 0;
+const SCANNING: u8 = 1;
+const DEDUPLICATING: u8 = 2;
+
+impl Progress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let (done, total) = (self.files_done.load(Relaxed), self.files_total.load(Relaxed));
+        let eta = match *self.started.lock().unwrap() {
+            Some(started) if done > 0 && done < total =>
+                Some(started.elapsed().mul_f64((total - done) as f64 / done as f64)),
+            _ => None,
+        };
+        ProgressSnapshot {
+            phase: match self.phase.load(Relaxed) {
+                SCANNING => Phase::Scanning,
+                DEDUPLICATING => Phase::Deduplicating,
+                _ => Phase::Idle,
+            },
+            files_scanned: self.files_scanned.load(Relaxed),
+            bytes_read: self.bytes_read.load(Relaxed),
+            groups_done: self.groups_done.load(Relaxed),
+            groups_total: self.groups_total.load(Relaxed),
+            links_made: self.links_made.load(Relaxed),
+            eta,
+        }
+    }
+
+    pub(crate) fn start_scanning(&self) {
+        for counter in [&self.files_scanned, &self.bytes_read, &self.groups_done, &self.groups_total,
+                        &self.files_done, &self.files_total, &self.links_made] {
+            counter.store(0, Relaxed);
+        }
+        *self.started.lock().unwrap() = None;
+        self.phase.store(SCANNING, Relaxed);
+    }
+    /// groups: number of sizes to deduplicate; files: number of files among them
+    pub(crate) fn start_deduplicating(&self, groups: u64, files: u64) {
+        self.groups_total.store(groups, Relaxed);
+        self.files_total.store(files, Relaxed);
+        *self.started.lock().unwrap() = Some(std::time::Instant::now());
+        self.phase.store(DEDUPLICATING, Relaxed);
+    }
+    pub(crate) fn idle(&self) {
+        self.phase.store(IDLE, Relaxed);
+    }
+
+    pub(crate) fn scanned(&self, files: u64) {
+        self.files_scanned.fetch_add(files, Relaxed);
+    }
+    pub(crate) fn read(&self, bytes: u64) {
+        self.bytes_read.fetch_add(bytes, Relaxed);
+    }
+    /// a group of files of one size was deduplicated
+    pub(crate) fn finished_group(&self, files: u64) {
+        self.groups_done.fetch_add(1, Relaxed);
+        self.files_done.fetch_add(files, Relaxed);
+    }
+    pub(crate) fn linked(&self) {
+        self.links_made.fetch_add(1, Relaxed);
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::error::{Error, ErrorTally, Operation};
+use crate::event::{Event, OnEvent};
+use crate::fs::{absolute_nofollow, metadata_nofollow, open_at, open_dir, open_nofollow, read_dir_names, stat_at, stat_fd, Metadata};
+
+
+
+pub type Registry = HashMap<u64, Vec<PathWithMetadata>>;
+
+/// walk targets into a registry of files by size, keeping only sizes shared by more than one file
+/// the target of each file is the index of the earliest target it was found under
+/// reports errors to on_event, and returns their tally
+pub fn scan(targets: Vec<PathWithMetadata>, cfg: &Config, on_event: OnEvent) -> (Registry, ErrorTally) {
+    let registry = std::sync::Mutex::new(Registry::new());
+    let errors = std::sync::Mutex::new(ErrorTally::default());
+    if let Some(progress) = &cfg.progress {
+        progress.start_scanning();
+    }
+    let queue = targets.into_iter().enumerate().map(|(target, mut pwmd)| {
+        pwmd.target = target;
+        let root_dev = pwmd.md().st_dev();
+        WalkItem { pwmd, parent: None, root_dev, depth: 0 }
+    }).collect();
+    rayon::scope(|scope| register(scope, queue, &registry, &errors, cfg, on_event));
+    if let Some(progress) = &cfg.progress {
+        progress.idle();
+    }
+    let mut registry = registry.into_inner().unwrap();
+    for files in registry.values_mut() { // walk order is nondeterministic
+        files.sort_unstable_by(|a, b| a.path.cmp(&b.path).then(a.target.cmp(&b.target)));
+        files.dedup_by(|a, b| a.path == b.path); // found under overlapping targets; earliest target stays
+    }
+    registry.retain(|_,files| files.len() >= 2);
+    (registry, errors.into_inner().unwrap())
+}
+
+
+/// a path waiting to be registered
+pub(crate) struct WalkItem {
+    pub(crate) pwmd: PathWithMetadata,
+    /// directory pwmd was found in, held open so it is opened relative to it; None for targets
+    pub(crate) parent: Option<std::sync::Arc<std::os::fd::OwnedFd>>,
+    /// device of the target pwmd was found under
+    pub(crate) root_dev: u64,
+    /// directory levels below the target
+    pub(crate) depth: usize,
+}
+
+/// register each path of queue or its contents if directory into registry, depth first
+/// directories are opened relative to their parent without following symlinks
+/// while this thread has no other pending tasks, half of the queue is handed to a task spawned on scope
+/// reports errors to on_event and records them in errors
+pub(crate) fn register<'s>(
+    scope: &rayon::Scope<'s>,
+    mut queue: Vec<WalkItem>,
+    registry: &'s std::sync::Mutex<Registry>,
+    errors: &'s std::sync::Mutex<ErrorTally>,
+    cfg: &'s Config,
+    on_event: OnEvent<'s>,
+) {
+    let report = |error: Error| {
+        on_event(Event::Error(&error));
+        errors.lock().unwrap().record(&error);
+    };
+    use std::os::fd::AsFd;
+    while let Some(WalkItem { pwmd, parent, root_dev, depth }) = queue.pop() {
+        if cfg.cancelled() {
+            return;
+        }
+
+        let mut files = Vec::new(); // registered together to lock registry once per directory
+        if pwmd.md().is_dir() {
+            if matches_any(&cfg.exclude, &pwmd.path) || cfg.max_depth.is_some_and(|max| depth >= max) {
+                continue;
+            }
+            let dir = match &parent {
+                Some(parent) => open_at(parent.as_fd(), pwmd.path.file_name().unwrap(), libc::O_RDONLY | libc::O_DIRECTORY, 0),
+                None => open_dir(&pwmd.path, libc::O_RDONLY),
+            };
+            match dir.and_then(|dir| Ok((read_dir_names(dir.as_fd())?, std::sync::Arc::new(dir)))) {
+                Ok((names, dir)) => for name in names {
+                    if let Some(progress) = &cfg.progress {
+                        progress.scanned(1);
+                    }
+                    match PathWithMetadata::new_at(dir.as_fd(), &name, pwmd.path.join(&name)) {
+                        Ok(child_pwmd) if cfg.one_file_system && child_pwmd.md().st_dev() != root_dev => {
+                            on_event(Event::SkippedMountPoint(&child_pwmd.path));
+                        },
+                        Ok(mut child_pwmd) => {
+                            child_pwmd.target = pwmd.target;
+                            let is_dir = child_pwmd.md().is_dir();
+                            match is_dir {
+                                true => queue.push(WalkItem {
+                                    pwmd: child_pwmd,
+                                    parent: Some(dir.clone()),
+                                    root_dev,
+                                    depth: depth + 1,
+                                }),
+                                false => files.push(child_pwmd),
+                            }
+                        },
+                        Err(error) => report(error),
+                    }
+                },
+                Err(error) => report(Error::io(Operation::ReadDir, pwmd.path.clone(), error)),
+            }
+        } else {
+            if let Some(progress) = &cfg.progress {
+                progress.scanned(1);
+            }
+            files.push(pwmd);
+        }
+
+        // symlinks are neither files nor directories by their own metadata
+        files.retain(|pwmd| {
+            pwmd.md().is_file()
+                && pwmd.md().st_size() >= cfg.min_size
+                && !matches_any(&cfg.exclude, &pwmd.path)
+                && (cfg.include.is_empty() || matches_any(&cfg.include, &pwmd.path))
+        });
+        if !files.is_empty() {
+            let mut registry = registry.lock().unwrap();
+            for pwmd in files {
+                let size = pwmd.md().st_size();
+                registry.entry(size).or_default().push(pwmd);
+            }
+        }
+
+        // let idle threads steal work
+        if queue.len() >= 2 && rayon_core::current_thread_has_pending_tasks() != Some(true) {
+            let half = queue.split_off(queue.len() / 2);
+            scope.spawn(move |scope| register(scope, half, registry, errors, cfg, on_event));
+        }
+    }
+}
+
+
+
+/// whether any pattern matches either the full path or the file name
+pub fn matches_any(patterns: &[glob::Pattern], path: &Path) -> bool {
+    let name = path.file_name().map(|name| name.to_string_lossy());
+    patterns.iter().any(|pattern| {
+        pattern.matches_path(path) || name.as_ref().is_some_and(|name| pattern.matches(name))
+    })
+}
+
+pub struct PathWithMetadata {
+    pub path: PathBuf,
+    /// index of the target this path was found under
+    pub target: usize,
+    md: std::cell::RefCell<Metadata>,
+}
+impl PathWithMetadata {
+    /// a relative path is made absolute as by absolute_nofollow, so a symlink is described rather than followed
+    pub fn new(path: PathBuf) -> Result<Self, Error> {
+        let path = absolute_nofollow(&path).map_err(|e| Error::io(Operation::Canonicalize, path, e))?;
+        let md = std::cell::RefCell::new(Self::get_md(&path)?);
+        Ok(PathWithMetadata{ path, target: 0, md })
+    }
+    /// path must be name joined onto the path of dir
+    pub fn new_at(dir: std::os::fd::BorrowedFd, name: &std::ffi::OsStr, path: PathBuf) -> Result<Self, Error> {
+        let md = match stat_at(dir, name) {
+            Ok(md) => md,
+            Err(e) => return Err(Error::io(Operation::Stat, path, e)),
+        };
+        Ok(PathWithMetadata{ path, target: 0, md: std::cell::RefCell::new(md) })
+    }
+    pub fn md<'a>(&'a self) -> std::cell::Ref<'a, Metadata> {
+        self.md.borrow()
+    }
+    pub fn reset_md(&self) -> Result<(), Error> {
+        *self.md.borrow_mut() = Self::get_md(&self.path)?;
+        Ok(())
+    }
+    /// fail if path no longer refers to the file described by the stored metadata
+    /// compares device, inode, size, mtime and optionally ctime of the opened file
+    pub fn check_unchanged(&self, check_ctime: bool) -> Result<(), Error> {
+        use std::os::fd::AsFd;
+        let now = open_nofollow(&self.path, libc::O_RDONLY | libc::O_NONBLOCK)
+            .and_then(|fd| stat_fd(fd.as_fd()))
+            .map_err(|e| Error::io(Operation::Open, &self.path, e))?;
+        let then = self.md();
+        let changed = |reason| Err(Error::Changed { path: self.path.clone(), reason });
+        if (now.st_dev(), now.st_ino()) != (then.st_dev(), then.st_ino()) {
+            return changed("was replaced");
+        }
+        if now.st_size() != then.st_size() {
+            return changed("changed size");
+        }
+        if (now.st_mtime(), now.st_mtime_nsec()) != (then.st_mtime(), then.st_mtime_nsec()) {
+            return changed("was modified");
+        }
+        if check_ctime && (now.st_ctime(), now.st_ctime_nsec()) != (then.st_ctime(), then.st_ctime_nsec()) {
+            return changed("changed metadata");
+        }
+        Ok(())
+    }
+    fn get_md(path: &Path) -> Result<Metadata, Error> {
+        metadata_nofollow(path).map_err(|e| Error::io(Operation::Stat, path, e))
+    }
+}
+impl AsRef<PathBuf> for PathWithMetadata {
+    fn as_ref(&self) -> &PathBuf {
+        &self.path
+    }
+}
+impl AsRef<Path> for PathWithMetadata {
+    fn as_ref(&self) -> &Path {
+        self.path.as_ref()
+    }
+}
+
+
+
+/// split paths into sets on the same device, in order of first appearance
+pub fn partition_by_device(
+    pwmds: Vec<PathWithMetadata>
+) -> Vec<Vec<PathWithMetadata>> {
+    let mut partitions: Vec<Vec<PathWithMetadata>> = Vec::new();
+    for pwmd in pwmds {
+        let dev = pwmd.md().st_dev();
+        match partitions.iter_mut().find(|partition| partition[0].md().st_dev() == dev) {
+            Some(partition) => partition.push(pwmd),
+            None => partitions.push(vec![pwmd]),
+        }
+    }
+    partitions
+}
+
+pub fn check_all_same_device(
+    pwmds: &[PathWithMetadata]
+) -> Result<(), Error> {
+    if pwmds.len() <= 1 {
+        return Ok(())
+    }
+    let mut by_dev: HashMap<u64, Vec<&PathWithMetadata>> = Default::default();
+    for pwmd in pwmds.iter() {
+        by_dev.entry(pwmd.md().st_dev()).or_default().push(pwmd);
+    }
+    if by_dev.len() <= 1 {
+        return Ok(());
+    }
+    let mut lines = Vec::with_capacity(1+by_dev.len());
+    lines.push(String::from("Device ids must all be the same; got paths on different devices:"));
+    lines.extend(by_dev.into_iter().map(|(dev,pwmds)| {
+        if pwmds.len() == 1 {
+            format!("  Device {}: {} path: {}", dev, pwmds.len(), &shlex::try_quote(&pwmds[0].path.to_string_lossy()).unwrap())
+        } else {
+            format!("  Device {}: {} paths", dev, pwmds.len())
+        }
+    }));
+    Err(Error::Usage(lines.join("\n")))
+}
+
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    #[test]
+    pub fn test_matches_any() {
+        let patterns = vec![glob::Pattern::new(".git").unwrap(), glob::Pattern::new("/srv/*/cache").unwrap()];
+        assert!(matches_any(&patterns, Path::new("/home/user/repo/.git")));
+        assert!(matches_any(&patterns, Path::new("/srv/www/cache")));
+        assert!(!matches_any(&patterns, Path::new("/home/user/repo/.gitignore")));
+    }
+    #[test]
+    pub fn test_scan_overlapping_targets() {
+        let dir = TempDir::new("scan");
+        std::fs::create_dir_all(dir.join("d/e")).unwrap();
+        std::fs::write(dir.join("d/e/a"), b"contents").unwrap();
+        std::fs::write(dir.join("d/b"), b"contents").unwrap();
+        let targets = [dir.join("d/e"), dir.join("d")].into_iter()
+            .map(|path| PathWithMetadata::new(path).unwrap())
+            .collect();
+        let (registry, errors) = scan(targets, &Config::builder().build(), &|_| {});
+        assert!(errors.is_empty());
+        let files = &registry[&8];
+        assert_eq!(files.len(), 2);
+        assert_eq!(files.iter().map(|pwmd| pwmd.target).collect::<Vec<_>>(), vec![1, 0]);
+    }
+    #[test]
+    pub fn test_scan_max_depth() {
+        let dir = TempDir::new("max-depth");
+        std::fs::create_dir_all(dir.join("s/t")).unwrap();
+        for name in ["a1", "a2", "s/b1", "s/b2", "s/t/c1", "s/t/c2"] {
+            std::fs::write(dir.join(name), b"contents").unwrap();
+        }
+        let found = |max_depth: Option<usize>| {
+            let cfg = Config::builder().max_depth(max_depth).build();
+            let (registry, errors) = scan(vec![PathWithMetadata::new(dir.to_path_buf()).unwrap()], &cfg, &|_| {});
+            assert!(errors.is_empty());
+            registry.get(&8).map_or(0, |files| files.len())
+        };
+        assert_eq!(found(Some(0)), 0);
+        assert_eq!(found(Some(1)), 2);
+        assert_eq!(found(Some(2)), 4);
+        assert_eq!(found(None), 6);
+    }
+    #[test]
+    pub fn test_scan_one_file_system() {
+        use std::os::unix::fs::MetadataExt;
+        let root_dev = std::fs::symlink_metadata("/").unwrap().dev();
+        let mut mount_points: Vec<PathBuf> = std::fs::read_dir("/").unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| std::fs::symlink_metadata(path).is_ok_and(|md| md.is_dir() && md.dev() != root_dev))
+            .collect();
+        if mount_points.is_empty() { // such as /proc, absent in some sandboxes
+            return;
+        }
+        let skipped = |one_file_system: bool| {
+            let skipped = std::sync::Mutex::new(Vec::new());
+            let on_event = |event: Event| if let Event::SkippedMountPoint(path) = event {
+                skipped.lock().unwrap().push(path.to_path_buf());
+            };
+            let cfg = Config::builder().one_file_system(one_file_system).max_depth(Some(1)).build();
+            scan(vec![PathWithMetadata::new("/".into()).unwrap()], &cfg, &on_event);
+            skipped.into_inner().unwrap()
+        };
+        let mut found = skipped(true);
+        found.sort();
+        mount_points.sort();
+        assert_eq!(found, mount_points);
+        assert!(skipped(false).is_empty());
+    }
+    #[test]
+    pub fn test_scan_stops_when_cancelled() {
+        let dir = TempDir::new("cancel");
+        for name in ["a", "b"] {
+            std::fs::write(dir.join(name), b"contents").unwrap();
+        }
+        let cancel = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let cfg = Config::builder().cancel(Some(cancel)).build();
+        assert!(cfg.cancelled());
+        let (registry, errors) = scan(vec![PathWithMetadata::new(dir.to_path_buf()).unwrap()], &cfg, &|_| {});
+        assert!(registry.is_empty() && errors.is_empty());
+    }
+}
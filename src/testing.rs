@@ -0,0 +1,26 @@
+use std::path::{Path, PathBuf};
+
+
+
+/// a directory for a test, removed with its contents when dropped, even if the test fails
+/// canonical, as required by functions that refuse symlinks in any component
+pub struct TempDir(PathBuf);
+impl TempDir {
+    /// name must be unique among the tests
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("lndups-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir.canonicalize().unwrap())
+    }
+}
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}